@@ -0,0 +1,838 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::diff::{self, FieldChange};
+use crate::dump;
+use crate::error::{Error, Result};
+use crate::export;
+use crate::http::{self, BearerToken, Http, Methods, Url};
+use crate::hydrolix::cluster;
+use crate::hydrolix::table;
+use crate::redact;
+
+// Set by the cluster, never sent back
+const SERVER_FIELDS: [&str; 4] = ["uuid", "created", "modified", "url"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+// One call to the config API.  Parents are referred to by path, because a
+// parent created earlier in the same plan has no UUID until it is applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Operation {
+    pub action: Action,
+    pub resource: String,
+    pub path: String,
+    // UUID of the live resource, for updates and deletes
+    pub uuid: Option<String>,
+    // What an update changes, for the dry-run output
+    pub fields: Vec<FieldChange>,
+    pub body: Option<Value>,
+    #[serde(skip)]
+    org: String,
+    #[serde(skip)]
+    project: Option<String>,
+    #[serde(skip)]
+    table: Option<String>,
+}
+
+// Operations in the order they must be applied: creates and updates from
+// the top down, then deletes from the bottom up.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Plan {
+    pub base_url: String,
+    pub operations: Vec<Operation>,
+    // `<resource> <path>` to the UUID of every resource that exists on the
+    // live cluster
+    #[serde(skip)]
+    ids: HashMap<String, String>,
+    // UUID of every storage in the desired state to its path, to point
+    // tables at storages created by the plan
+    #[serde(skip)]
+    storages: HashMap<String, String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    // Only create and update, never remove what is on the cluster
+    pub fn without_deletes(mut self) -> Self {
+        self.operations.retain(|v| v.action != Action::Delete);
+        self
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        export::to_pretty_json(self, &[])
+    }
+}
+
+// The dry-run output
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operations.is_empty() {
+            return writeln!(f, "{} is up to date", self.base_url);
+        }
+        writeln!(f, "Plan for {}:", self.base_url)?;
+        for o in &self.operations {
+            let action = match o.action {
+                Action::Create => "create",
+                Action::Update => "update",
+                Action::Delete => "delete",
+            };
+            writeln!(f, "{action} {} {}", o.resource, o.path)?;
+            for v in &o.fields {
+                writeln!(f, "  {v}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read a desired state written by `export::export_tree`, or a single JSON
+// document as printed by `dump`
+pub fn load_desired(path: &Path) -> Result<cluster::Cluster> {
+    if path.is_dir() {
+        return export::load_tree(path);
+    }
+    match fs::read_to_string(path) {
+        Ok(v) => http::from_json(&path.display().to_string(), &v),
+        Err(e) => Err(Error::Io {
+            path: path.display().to_string(),
+            source: e,
+        }),
+    }
+}
+
+// Compute what turns `live` into `desired`.  Resources are matched like in
+// `diff::diff`, by UUID then by name, so a dump of one cluster can be
+// applied to another.  Orgs are never created or deleted, every org in
+// `desired` must exist on the live cluster.
+pub fn plan(desired: &cluster::Cluster, live: &cluster::Cluster) -> Result<Plan> {
+    let mut planner = Planner::default();
+
+    let desired_orgs = desired.orgs.as_deref().unwrap_or_default();
+    let live_orgs = live.orgs.as_deref().unwrap_or_default();
+    for (d, l) in diff::pair(desired_orgs, live_orgs, |v| (&v.uuid, &v.name)) {
+        let (d, l) = match (d, l) {
+            (Some(d), Some(l)) => (d, l),
+            (Some(d), None) => {
+                return Err(Error::Config(format!(
+                    "Org {} does not exist on {}",
+                    d.name, live.base_url
+                )))
+            }
+            _ => continue,
+        };
+        let org = Parents {
+            org: l.uuid.to_string(),
+            project: None,
+            table: None,
+        };
+
+        let storages = diff::pair(
+            d.storages.as_deref().unwrap_or_default(),
+            l.storages.as_deref().unwrap_or_default(),
+            |v| (&v.uuid, &v.name),
+        );
+        for (s, t) in storages {
+            let path = format!("{}/{}", l.name, diff::either(s, t, |v| &v.name));
+            planner.step("storage", &path, &org, s, t, |v| &v.uuid, &[])?;
+            if let Some(s) = s {
+                planner
+                    .storages
+                    .insert(s.uuid.to_string(), path.to_string());
+            }
+            if let Some(t) = t {
+                planner.live_storages.insert(t.uuid.to_string());
+            }
+        }
+
+        let projects = diff::pair(
+            d.projects.as_deref().unwrap_or_default(),
+            l.projects.as_deref().unwrap_or_default(),
+            |v| (&v.uuid, &v.name),
+        );
+        for (p, q) in projects {
+            let project_path = format!("{}/{}", l.name, diff::either(p, q, |v| &v.name));
+            let children = ["tables", "functions"];
+            planner.step("project", &project_path, &org, p, q, |v| &v.uuid, &children)?;
+            let project = Parents {
+                project: Some(project_path.to_string()),
+                ..org.clone()
+            };
+
+            let functions = diff::pair(
+                p.and_then(|v| v.functions.as_deref()).unwrap_or_default(),
+                q.and_then(|v| v.functions.as_deref()).unwrap_or_default(),
+                |v| (&v.uuid, &v.name),
+            );
+            for (s, t) in functions {
+                let path = format!("{project_path}/{}", diff::either(s, t, |v| &v.name));
+                planner.step("function", &path, &project, s, t, |v| &v.uuid, &[])?;
+            }
+
+            let tables = diff::pair(
+                p.and_then(|v| v.tables.as_deref()).unwrap_or_default(),
+                q.and_then(|v| v.tables.as_deref()).unwrap_or_default(),
+                |v| (&v.uuid, &v.name),
+            );
+            for (s, t) in tables {
+                let table_path = format!("{project_path}/{}", diff::either(s, t, |v| &v.name));
+                let s = match s {
+                    Some(v) => Some(planner.with_live_storages(v, &table_path, &live.base_url)?),
+                    None => None,
+                };
+                let s = s.as_ref();
+                let children = ["transforms"];
+                planner.step("table", &table_path, &project, s, t, |v| &v.uuid, &children)?;
+                let table = Parents {
+                    table: Some(table_path.to_string()),
+                    ..project.clone()
+                };
+
+                let transforms = diff::pair(
+                    s.and_then(|v| v.transforms.as_deref()).unwrap_or_default(),
+                    t.and_then(|v| v.transforms.as_deref()).unwrap_or_default(),
+                    |v| (&v.uuid, &v.name),
+                );
+                for (u, w) in transforms {
+                    let path = format!("{table_path}/{}", diff::either(u, w, |v| &v.name));
+                    planner.step("transform", &path, &table, u, w, |v| &v.uuid, &[])?;
+                }
+            }
+        }
+    }
+
+    // Children go before their parents, storages last as tables may use them
+    let rank = |resource: &str| match resource {
+        "transform" => 0,
+        "table" => 1,
+        "function" => 2,
+        "project" => 3,
+        _ => 4,
+    };
+    planner.deletes.sort_by_key(|v| rank(&v.resource));
+
+    let mut operations = planner.operations;
+    operations.append(&mut planner.deletes);
+    Ok(Plan {
+        base_url: live.base_url.to_string(),
+        operations,
+        ids: planner.ids,
+        storages: planner.storages,
+    })
+}
+
+// Dump the live cluster and plan against it
+pub async fn plan_live(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    desired: &cluster::Cluster,
+) -> Result<Plan> {
+    let live = dump::dump_with_client(client, auth_token).await?;
+    plan(desired, &live)
+}
+
+// Execute the plan.  Stops at the first failure, operations before it stay
+// applied.
+pub async fn apply(client: &HydrolixClient, auth_token: &HydrolixToken, plan: &Plan) -> Result<()> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    apply_helper(&methods, auth_token, plan).await
+}
+
+pub async fn apply_helper(
+    methods: &impl Methods,
+    auth_token: &HydrolixToken,
+    plan: &Plan,
+) -> Result<()> {
+    let bearer = BearerToken::from(auth_token);
+    let mut ids = plan.ids.clone();
+
+    for o in &plan.operations {
+        let project = resolve(&ids, "project", o.project.as_deref())?;
+        let table = resolve(&ids, "table", o.table.as_deref())?;
+
+        let mut collection = format!("https://{}/config/v1/orgs/{}/", auth_token.base_url, o.org);
+        if let Some(v) = &project {
+            collection += &format!("projects/{v}/");
+        }
+        if let Some(v) = &table {
+            collection += &format!("tables/{v}/");
+        }
+        collection += match o.resource.as_str() {
+            "storage" => "storages/",
+            "project" => "projects/",
+            "function" => "functions/",
+            "table" => "tables/",
+            _ => "transforms/",
+        };
+
+        // Point the body at the parents and storages on this cluster
+        let mut body = o.body.clone().unwrap_or(Value::Null);
+        if let Some(storage_map) = body.pointer_mut("/settings/storage_map") {
+            map_storages(storage_map, |uuid| match plan.storages.get(uuid) {
+                Some(path) => Ok(resolve(&ids, "storage", Some(path))?.unwrap_or_default()),
+                None => Ok(uuid.to_string()),
+            })?;
+        }
+        if let Value::Object(map) = &mut body {
+            let parents = [
+                ("org", Some(&o.org)),
+                ("project", project.as_ref()),
+                ("table", table.as_ref()),
+            ];
+            for (key, uuid) in parents {
+                if let (true, Some(uuid)) = (map.contains_key(key), uuid) {
+                    map.insert(key.to_string(), Value::String(uuid.to_string()));
+                }
+            }
+        }
+
+        let item = |uuid: &Option<String>| {
+            Url::new(format!(
+                "{collection}{}/",
+                uuid.as_deref().unwrap_or_default()
+            ))
+        };
+        match o.action {
+            Action::Create => {
+                let response = methods
+                    .post_json(&Url::new(collection.to_string()), &bearer, &body)
+                    .await?;
+                let created: Value = response.json()?;
+                if let Some(uuid) = created.get("uuid").and_then(|v| v.as_str()) {
+                    ids.insert(format!("{} {}", o.resource, o.path), uuid.to_string());
+                }
+            }
+            Action::Update => {
+                methods.put_json(&item(&o.uuid), &bearer, &body).await?;
+            }
+            Action::Delete => {
+                methods.delete(&item(&o.uuid), &bearer).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Rewrite the storage UUIDs of a table's `settings.storage_map`
+pub(crate) fn map_storages(
+    storage_map: &mut Value,
+    mut f: impl FnMut(&str) -> Result<String>,
+) -> Result<()> {
+    let Value::Object(map) = storage_map else {
+        return Ok(());
+    };
+    if let Some(Value::String(v)) = map.get_mut("default_storage_id") {
+        *v = f(v)?;
+    }
+    if let Some(Value::Object(mapping)) = map.get_mut("column_value_mapping") {
+        for (uuid, values) in std::mem::take(mapping) {
+            mapping.insert(f(&uuid)?, values);
+        }
+    }
+    Ok(())
+}
+
+fn resolve(
+    ids: &HashMap<String, String>,
+    resource: &str,
+    path: Option<&str>,
+) -> Result<Option<String>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    match ids.get(&format!("{resource} {path}")) {
+        Some(v) => Ok(Some(v.to_string())),
+        None => Err(Error::Config(format!(
+            "No UUID for {resource} {path}, was it created?"
+        ))),
+    }
+}
+
+#[derive(Clone)]
+struct Parents {
+    org: String,
+    project: Option<String>,
+    table: Option<String>,
+}
+
+#[derive(Default)]
+struct Planner {
+    operations: Vec<Operation>,
+    deletes: Vec<Operation>,
+    ids: HashMap<String, String>,
+    storages: HashMap<String, String>,
+    live_storages: HashSet<String>,
+}
+
+impl Planner {
+    // The table with the storages it uses as they are known on the live
+    // cluster.  A storage that is neither desired nor live fails the plan.
+    fn with_live_storages(
+        &self,
+        desired: &table::Table,
+        path: &str,
+        base_url: &str,
+    ) -> Result<table::Table> {
+        let mut desired = desired.clone();
+        if let Some(storage_map) = &mut desired.settings.storage_map {
+            map_storages(storage_map, |uuid| {
+                match self.storages.get(uuid) {
+                // Created by the plan when not matched, `apply` fills it in
+                Some(v) => match self.ids.get(&format!("storage {v}")) {
+                    Some(v) => Ok(v.to_string()),
+                    None => Ok(uuid.to_string()),
+                },
+                None if self.live_storages.contains(uuid) => Ok(uuid.to_string()),
+                None => Err(Error::Config(format!(
+                    "Table {path} uses storage {uuid}, which is neither in the desired state nor on {base_url}"
+                ))),
+            }
+            })?;
+        }
+        Ok(desired)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn step<T: Serialize>(
+        &mut self,
+        resource: &str,
+        path: &str,
+        parents: &Parents,
+        desired: Option<&T>,
+        live: Option<&T>,
+        uuid: impl Fn(&T) -> &String,
+        children: &[&str],
+    ) -> Result<()> {
+        let operation = |action, uuid: Option<&String>, fields, body| Operation {
+            action,
+            resource: resource.to_string(),
+            path: path.to_string(),
+            uuid: uuid.cloned(),
+            fields,
+            body,
+            org: parents.org.to_string(),
+            project: parents.project.clone(),
+            table: parents.table.clone(),
+        };
+
+        // Redacted secrets keep their live value, e.g. in a tree from
+        // `GitSnapshot`, there's nothing else to write
+        let desired = match desired {
+            Some(d) => {
+                let mut d = serde_json::to_value(d).unwrap_or(Value::Null);
+                let l = live.map(|v| serde_json::to_value(v).unwrap_or(Value::Null));
+                keep_secrets(&mut d, l.as_ref(), &format!("{resource} {path}"), "")?;
+                Some(d)
+            }
+            None => None,
+        };
+
+        match (desired.as_ref(), live) {
+            (Some(d), Some(l)) => {
+                self.ids
+                    .insert(format!("{resource} {path}"), uuid(l).to_string());
+                let mut fields = vec![];
+                diff::diff_value(
+                    &mut fields,
+                    "",
+                    &diff::to_value(l, children),
+                    &diff::to_value(d, children),
+                );
+                if !fields.is_empty() {
+                    let body = body(d, children);
+                    self.operations.push(operation(
+                        Action::Update,
+                        Some(uuid(l)),
+                        fields,
+                        Some(body),
+                    ));
+                }
+            }
+            (Some(d), None) => {
+                let body = body(d, children);
+                self.operations
+                    .push(operation(Action::Create, None, vec![], Some(body)));
+            }
+            (None, Some(l)) => {
+                self.ids
+                    .insert(format!("{resource} {path}"), uuid(l).to_string());
+                self.deletes
+                    .push(operation(Action::Delete, Some(uuid(l)), vec![], None));
+            }
+            (None, None) => (),
+        }
+        Ok(())
+    }
+}
+
+// Replace every redacted value in `desired` with the value at the same place
+// in `live`.  Without a live value the secret is lost, so it fails.
+fn keep_secrets(desired: &mut Value, live: Option<&Value>, name: &str, field: &str) -> Result<()> {
+    match desired {
+        Value::String(v) if redact::is_redacted(v) => match live {
+            Some(l) => *desired = l.clone(),
+            None => {
+                return Err(Error::Config(format!(
+                    "The {name} has a redacted value in {field}, set the secret itself to apply it"
+                )))
+            }
+        },
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                let field = format!("{field}/{k}");
+                keep_secrets(v, live.and_then(|l| l.get(k)), name, &field)?;
+            }
+        }
+        Value::Array(list) => {
+            for (i, v) in list.iter_mut().enumerate() {
+                let field = format!("{field}/{i}");
+                keep_secrets(v, live.and_then(|l| l.get(i)), name, &field)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn body<T: Serialize>(value: &T, children: &[&str]) -> Value {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    if let Value::Object(map) = &mut value {
+        for key in children.iter().chain(SERVER_FIELDS.iter()) {
+            map.remove(*key);
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_helper, load_desired, plan, Action};
+    use crate::dump::{dump_helper, DumpOptions};
+    use crate::http::Method;
+    use crate::hydrolix::cluster::Cluster;
+    use crate::mock;
+    use crate::redact;
+    use crate::snapshot::GitSnapshot;
+    use serde_json::json;
+
+    async fn mock_cluster() -> Cluster {
+        match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+            Ok((v, _)) => *v,
+            Err(e) => panic!("Failed to dump: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_up_to_date() {
+        let live = mock_cluster().await;
+        let mut desired = live.clone();
+        // Server managed fields don't count
+        if let Some(orgs) = &mut desired.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects[0].modified = "2030-01-01T00:00:00Z".to_string();
+            }
+        }
+        match plan(&desired, &live) {
+            Ok(v) => assert!(v.is_empty() && v.to_string() == "h is up to date\n"),
+            Err(e) => panic!("Failed to plan: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_unknown_org() {
+        let live = mock_cluster().await;
+        let mut desired = live.clone();
+        if let Some(orgs) = &mut desired.orgs {
+            orgs[0].uuid = "other".to_string();
+            orgs[0].name = "other".to_string();
+        }
+        assert!(plan(&desired, &live).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_apply() {
+        let live = mock_cluster().await;
+        let mut desired = live.clone();
+        let Some(orgs) = &mut desired.orgs else {
+            panic!("No orgs");
+        };
+        let Some(projects) = &mut orgs[0].projects else {
+            panic!("No projects");
+        };
+        // A new project with a table and a transform, from another cluster
+        let mut new_project = projects[1].clone();
+        new_project.name = "p3".to_string();
+        new_project.uuid = "elsewhere".to_string();
+        projects.push(new_project);
+        projects.remove(1);
+        let Some(tables) = &mut projects[0].tables else {
+            panic!("No tables");
+        };
+        tables[0].settings.merge.enabled = false;
+
+        let result = match plan(&desired, &live) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to plan: {e}"),
+        };
+        let summary: Vec<(Action, &str, &str)> = result
+            .operations
+            .iter()
+            .map(|v| (v.action, v.resource.as_str(), v.path.as_str()))
+            .collect();
+        assert!(
+            summary
+                == vec![
+                    (Action::Update, "table", "org1/p1/t1"),
+                    (Action::Create, "project", "org1/p3"),
+                    (Action::Create, "table", "org1/p3/t3"),
+                    (Action::Create, "transform", "org1/p3/t3/t3_transform"),
+                    (Action::Delete, "transform", "org1/p2/t3/t3_transform"),
+                    (Action::Delete, "table", "org1/p2/t3"),
+                    (Action::Delete, "project", "org1/p2"),
+                ]
+        );
+        assert!(result.operations[0].fields[0].field == "settings.merge.enabled");
+        assert!(result
+            .to_string()
+            .contains("update table org1/p1/t1\n  settings.merge.enabled: true -> false\n"));
+        assert!(result.clone().without_deletes().operations.len() == 4);
+
+        let base = "https://h/config/v1/orgs/o1";
+        let methods = mock::MockMethods::default()
+            .with_response(
+                Method::PUT,
+                &format!("{base}/projects/p1/tables/t1/"),
+                200,
+                "{}",
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/"),
+                201,
+                &json!({"uuid": "new-p3"}).to_string(),
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new-p3/tables/"),
+                201,
+                &json!({"uuid": "new-t3"}).to_string(),
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new-p3/tables/new-t3/transforms/"),
+                201,
+                "{}",
+            )
+            .with_response(
+                Method::DELETE,
+                &format!("{base}/projects/p2/tables/t3/transforms/t3x/"),
+                204,
+                "",
+            )
+            .with_response(
+                Method::DELETE,
+                &format!("{base}/projects/p2/tables/t3/"),
+                204,
+                "",
+            )
+            .with_response(Method::DELETE, &format!("{base}/projects/p2/"), 204, "");
+        if let Err(e) = apply_helper(&methods, &mock::token(), &result).await {
+            panic!("Failed to apply: {e}");
+        }
+        assert!(methods.writes().len() == 7);
+
+        // The new table points at the new project, without server fields
+        let requests = methods.requests();
+        let Some(body) = &requests[2].body else {
+            panic!("No body");
+        };
+        assert!(body["project"] == "new-p3");
+        assert!(body.get("uuid").is_none() && body.get("transforms").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_apply_redacted_snapshot() {
+        let live = mock_cluster().await;
+        let root = std::env::temp_dir().join(format!("hdx_apply_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let snapshot = GitSnapshot::new(&root);
+        if let Err(e) = snapshot.snapshot(std::slice::from_ref(&live)).await {
+            panic!("Failed to snapshot: {e}");
+        }
+        let mut desired = match load_desired(&root.join("h")) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to load: {e}"),
+        };
+        let _ = std::fs::remove_dir_all(&root);
+
+        // The redacted secrets are not changes
+        match plan(&desired, &live) {
+            Ok(v) => assert!(v.is_empty()),
+            Err(e) => panic!("Failed to plan: {e}"),
+        }
+
+        let Some(orgs) = &mut desired.orgs else {
+            panic!("No orgs");
+        };
+        let Some(projects) = &mut orgs[0].projects else {
+            panic!("No projects");
+        };
+        let Some(tables) = &mut projects[0].tables else {
+            panic!("No tables");
+        };
+        assert!(tables[0].settings.stream.token_list[0]
+            .as_str()
+            .is_some_and(redact::is_redacted));
+        tables[0].settings.merge.enabled = false;
+        let result = match plan(&desired, &live) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to plan: {e}"),
+        };
+        assert!(result.operations.len() == 1);
+        assert!(result.operations[0].fields.len() == 1);
+
+        let methods = mock::MockMethods::default().with_response(
+            Method::PUT,
+            "https://h/config/v1/orgs/o1/projects/p1/tables/t1/",
+            200,
+            "{}",
+        );
+        if let Err(e) = apply_helper(&methods, &mock::token(), &result).await {
+            panic!("Failed to apply: {e}");
+        }
+        let requests = methods.requests();
+        let Some(body) = &requests[0].body else {
+            panic!("No body");
+        };
+        assert!(body["settings"]["stream"]["token_list"] == json!(["stream-secret"]));
+        assert!(!body.to_string().contains(redact::REDACTED_PREFIX));
+
+        // A new table has no live secret to keep
+        let Some(projects) = desired.orgs.as_mut().and_then(|v| v[0].projects.as_mut()) else {
+            panic!("No projects");
+        };
+        let Some(tables) = &mut projects[0].tables else {
+            panic!("No tables");
+        };
+        tables[0].uuid = "elsewhere".to_string();
+        tables[0].name = "t9".to_string();
+        match plan(&desired, &live) {
+            Ok(_) => panic!("A redacted secret should not be created"),
+            Err(e) => assert!(e.to_string().contains("table org1/p1/t9")),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_storage_references() {
+        let mut live = mock_cluster().await;
+        let mut desired = live.clone();
+        let set_storage_map = |cluster: &mut Cluster, value| {
+            let Some(orgs) = &mut cluster.orgs else {
+                panic!("No orgs");
+            };
+            let Some(projects) = &mut orgs[0].projects else {
+                panic!("No projects");
+            };
+            let Some(tables) = &mut projects[0].tables else {
+                panic!("No tables");
+            };
+            tables[0].settings.storage_map = Some(value);
+        };
+        set_storage_map(&mut live, json!({"default_storage_id": "s1"}));
+
+        // From another cluster: `s1` under another UUID and a new `s2`
+        let Some(orgs) = &mut desired.orgs else {
+            panic!("No orgs");
+        };
+        let Some(storages) = &mut orgs[0].storages else {
+            panic!("No storages");
+        };
+        storages[0].uuid = "src-s1".to_string();
+        let mut s2 = storages[0].clone();
+        s2.name = "s2".to_string();
+        s2.uuid = "src-s2".to_string();
+        storages.push(s2);
+        set_storage_map(
+            &mut desired,
+            json!({
+                "default_storage_id": "src-s1",
+                "column_name": "tier",
+                "column_value_mapping": {"src-s2": ["cold"]},
+            }),
+        );
+
+        let result = match plan(&desired, &live) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to plan: {e}"),
+        };
+        let summary: Vec<(Action, &str, &str)> = result
+            .operations
+            .iter()
+            .map(|v| (v.action, v.resource.as_str(), v.path.as_str()))
+            .collect();
+        assert!(
+            summary
+                == vec![
+                    (Action::Create, "storage", "org1/s2"),
+                    (Action::Update, "table", "org1/p1/t1"),
+                ]
+        );
+        // `src-s1` is the live `s1`, so the default storage doesn't change
+        let fields: Vec<&str> = result.operations[1]
+            .fields
+            .iter()
+            .map(|v| v.field.as_str())
+            .collect();
+        assert!(!fields.contains(&"settings.storage_map.default_storage_id"));
+
+        let base = "https://h/config/v1/orgs/o1";
+        let methods = mock::MockMethods::default()
+            .with_response(
+                Method::POST,
+                &format!("{base}/storages/"),
+                201,
+                &json!({"uuid": "new-s2"}).to_string(),
+            )
+            .with_response(
+                Method::PUT,
+                &format!("{base}/projects/p1/tables/t1/"),
+                200,
+                "{}",
+            );
+        if let Err(e) = apply_helper(&methods, &mock::token(), &result).await {
+            panic!("Failed to apply: {e}");
+        }
+        let requests = methods.requests();
+        let Some(body) = &requests[1].body else {
+            panic!("No body");
+        };
+        let storage_map = &body["settings"]["storage_map"];
+        assert!(storage_map["default_storage_id"] == "s1");
+        assert!(storage_map["column_value_mapping"]["new-s2"] == json!(["cold"]));
+
+        // A storage that exists nowhere
+        let mut broken = live.clone();
+        set_storage_map(&mut broken, json!({"default_storage_id": "nowhere"}));
+        match plan(&broken, &live) {
+            Ok(_) => panic!("The storage does not exist"),
+            Err(e) => assert!(e.to_string().contains("uses storage nowhere")),
+        }
+    }
+}
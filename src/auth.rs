@@ -1,11 +1,33 @@
+use async_trait::async_trait;
 use once_cell::sync::Lazy;
 use reqwest::header::CONTENT_TYPE;
-use reqwest::Client;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
 use tokio::time::Instant;
 
+use crate::client::HydrolixClient;
+use crate::error::{Error, Result};
+use crate::http::{BearerToken, Methods, Request, Response, Url};
+use crate::retry::RetryPolicy;
+
+// Renew tokens this long before they actually expire
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+// Lifetime assumed when the login response does not include `expires_in`
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// How long the background refresher waits before retrying a failed login
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Shortest sleep of the background refresher between two logins
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 pub struct Org {
@@ -22,6 +44,9 @@ pub struct HydrolixToken {
     pub value: String,
     pub org_list: Vec<Org>,
     pub expires_at: Instant,
+    // How long the token was valid when issued, unknown for tokens not
+    // obtained from a login
+    pub lifetime: Option<Duration>,
     pub hits: usize,
     pub base_url: String,
 }
@@ -39,6 +64,7 @@ impl HydrolixToken {
             value: "".to_string(),
             org_list: vec![],
             expires_at: Instant::now(),
+            lifetime: None,
             hits: 0,
             base_url: "".to_string(),
         }
@@ -49,21 +75,88 @@ impl HydrolixToken {
             None => "".to_string(),
         }
     }
+
+    // A token is fresh if it is still valid `margin` from now
+    pub fn is_fresh(&self, margin: Duration) -> bool {
+        Instant::now() + self.refresh_margin(margin) < self.expires_at
+    }
+
+    // `margin`, but at most half the lifetime, so a short lived token is
+    // still used for a while before it is renewed
+    pub fn refresh_margin(&self, margin: Duration) -> Duration {
+        match self.lifetime {
+            Some(v) => margin.min(v / 2),
+            None => margin,
+        }
+    }
+}
+
+// Tokens are cached per cluster and per user, so several `HydrolixAuth`
+// instances can be used side by side without clobbering each other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TokenKey {
+    base_url: String,
+    username: String,
 }
 
 #[allow(dead_code)]
-static TOKEN_CACHE: Lazy<Mutex<HydrolixToken>> = Lazy::new(|| Mutex::new(HydrolixToken::new()));
+static TOKEN_CACHE: Lazy<Mutex<HashMap<TokenKey, HydrolixToken>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct HydrolixAuth {
     base_url: String,
     username: String,
     password: String,
-    http_client: Client,
+    client: HydrolixClient,
     token: HydrolixToken,
+    refresh_margin: Duration,
+    default_lifetime: Duration,
+}
+
+// Never print the password, it ends up in logs
+impl fmt::Debug for HydrolixAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HydrolixAuth")
+            .field("base_url", &self.base_url)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("refresh_margin", &self.refresh_margin)
+            .field("default_lifetime", &self.default_lifetime)
+            .field("client", &self.client)
+            .finish_non_exhaustive()
+    }
 }
 
+// Failure to log in to a cluster.  Deliberately has no room for the password.
+#[derive(Debug, Clone)]
+pub struct AuthError {
+    pub url: String,
+    pub username: String,
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to authenticate: url={} username={}",
+            self.url, self.username
+        )?;
+        if let Some(status) = self.status {
+            write!(f, " status={status}")?;
+        }
+        if !self.message.is_empty() {
+            write!(f, " {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct AuthToken {
@@ -91,32 +184,84 @@ impl HydrolixAuth {
             value: "".to_string(),
             org_list: vec![],
             expires_at: Instant::now(),
+            lifetime: None,
             hits: 0,
         };
-        let mut cache = TOKEN_CACHE.lock().await;
-        *cache = token.clone();
 
         HydrolixAuth {
             base_url: base_url.to_string(),
             username: username.to_string(),
             password: password.to_string(),
-            http_client: reqwest::Client::new(),
+            client: HydrolixClient::shared(),
             token: token.clone(),
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            default_lifetime: DEFAULT_TOKEN_LIFETIME,
         }
     }
 
+    // Log in through this client instead of the shared one
+    pub fn with_client(mut self, client: HydrolixClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Retry policy for the login request
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.client = self.client.with_retry_policy(retry);
+        self
+    }
+
+    // Renew the token when it is within `margin` of expiring
+    pub fn with_refresh_margin(mut self, margin: Duration) -> Self {
+        self.refresh_margin = margin;
+        self
+    }
+
+    // Lifetime to assume when the cluster does not report `expires_in`
+    pub fn with_default_lifetime(mut self, lifetime: Duration) -> Self {
+        self.default_lifetime = lifetime;
+        self
+    }
+
     pub fn get_base_url(self) -> String {
         self.token.base_url.to_string()
     }
 
-    pub async fn get_token(mut self) -> Result<HydrolixToken, String> {
+    fn cache_key(&self) -> TokenKey {
+        TokenKey {
+            base_url: self.base_url.to_string(),
+            username: self.username.to_string(),
+        }
+    }
+
+    fn auth_error(&self, url: &str, status: Option<u16>, message: String) -> Error {
+        Error::Auth(AuthError {
+            url: url.to_string(),
+            username: self.username.to_string(),
+            status,
+            message,
+        })
+    }
+
+    pub async fn get_token(self) -> Result<HydrolixToken> {
+        let methods = Login {
+            client: self.client.clone(),
+        };
+        self.get_token_helper(&methods).await
+    }
+
+    // Same as `get_token`, logging in through `methods`
+    pub async fn get_token_helper(mut self, methods: &impl Methods) -> Result<HydrolixToken> {
+        let key = self.cache_key();
         {
             let mut cache = TOKEN_CACHE.lock().await;
 
-            // Check if token is cached and not expired
-            if cache.expires_at >= Instant::now() {
-                cache.hits += 1;
-                return Ok(cache.clone());
+            // Check if token is cached and not about to expire
+            if let Some(cached) = cache.get_mut(&key) {
+                if cached.is_fresh(self.refresh_margin) {
+                    cached.hits += 1;
+                    return Ok(cached.clone());
+                }
             }
         }
 
@@ -129,82 +274,181 @@ impl HydrolixAuth {
             "password": self.password,
         });
 
-        // Send the authentication request
-        let response = self
-            .http_client
-            .post(url.clone())
-            .header(CONTENT_TYPE, "application/json")
-            .timeout(Duration::from_secs(60))
-            .json(&payload)
-            .send()
-            .await;
-
-        let response2 = match response {
-            Ok(v) => v, // Capture the response on success
-            Err(e) => {
-                return Err(format!(
-                    "{}.{} Failed to authenticate: url={url} username={} password={} {e}",
-                    file!(),
-                    line!(),
-                    self.username,
-                    self.password
-                ))
-            }
+        // No bearer token, the login is what gets one
+        let request = Request::post(&Url::new(url), &BearerToken::new(""), &payload);
+        let response = match methods.send(request).await {
+            Ok(v) => v,
+            Err(e) => return Err(self.auth_error(url, None, e.to_string())),
         };
 
         // Check if the authentication was successful
-        if !response2.status().is_success() {
-            return Err(format!(
-                "{}.{} Failed to authenticate: url={url} username={} password={}",
-                file!(),
-                line!(),
-                self.username,
-                self.password
-            ));
+        if !response.is_success() {
+            return Err(self.auth_error(url, Some(response.status), String::new()));
         }
 
-        let payload = match response2.text().await {
+        let parsed: ParsedResponse = match serde_json::from_str(&response.body) {
             Ok(v) => v,
             Err(e) => {
-                return Err(format!(
-                    "{}.{} Failed to get json data {e}",
-                    file!(),
-                    line!()
+                return Err(self.auth_error(
+                    url,
+                    Some(response.status),
+                    format!("Failed to parse data {e}"),
                 ))
             }
         };
 
-        let parsed: ParsedResponse = match serde_json::from_str(&payload) {
-            Ok(v) => v,
-            Err(e) => return Err(format!("{}.{} Failed to parse data {e}", file!(), line!())),
-        };
-
         for o in &parsed.orgs {
             self.token.org_list.push(o.clone());
         }
         self.token.value = parsed.auth_token.access_token.to_string();
-        if let Some(v) = parsed.auth_token.expires_in {
-            self.token.expires_at = Instant::now() + Duration::from_secs(v)
-        }
+        let lifetime = match parsed.auth_token.expires_in {
+            Some(v) => Duration::from_secs(v),
+            None => self.default_lifetime,
+        };
+        self.token.expires_at = Instant::now() + lifetime;
+        self.token.lifetime = Some(lifetime);
 
         self.token.base_url = self.base_url.to_string();
 
         {
             let mut cache = TOKEN_CACHE.lock().await;
 
-            *cache = self.token;
+            cache.insert(key, self.token.clone());
+
+            Ok(self.token)
+        }
+    }
+
+    // Log in once, then keep the token fresh from a background task until the
+    // returned `TokenRefresher` is dropped.
+    pub async fn spawn_refresher(self) -> Result<TokenRefresher> {
+        let methods = Arc::new(Login {
+            client: self.client.clone(),
+        });
+        self.spawn_refresher_helper(methods).await
+    }
+
+    // Same as `spawn_refresher`, logging in through `methods`
+    pub async fn spawn_refresher_helper<M: Methods + 'static>(
+        self,
+        methods: Arc<M>,
+    ) -> Result<TokenRefresher> {
+        let token = self.clone().get_token_helper(methods.as_ref()).await?;
+        let (sender, receiver) = watch::channel(token.clone());
+
+        let handle = tokio::spawn(async move {
+            let mut token = token;
+            loop {
+                tokio::time::sleep_until(refresh_at(&token, self.refresh_margin)).await;
+
+                match self.clone().get_token_helper(methods.as_ref()).await {
+                    Ok(v) => {
+                        token = v.clone();
+                        if sender.send(v).is_err() {
+                            // Every receiver is gone, nobody needs the token anymore
+                            return;
+                        }
+                    }
+                    Err(_) => tokio::time::sleep(REFRESH_RETRY_DELAY).await,
+                }
+            }
+        });
+
+        Ok(TokenRefresher { receiver, handle })
+    }
+}
+
+// Transport of the login.  Unlike other POSTs a login is safe to send twice,
+// so it goes through the retry policy of the client.
+struct Login {
+    client: HydrolixClient,
+}
+
+#[async_trait]
+impl Methods for Login {
+    async fn send(&self, request: Request) -> Result<Response> {
+        let url = request.url.to_string();
+        let body = request.body.clone().unwrap_or_default();
+        let response = self
+            .client
+            .retry()
+            .send(|| {
+                self.client
+                    .http_client()
+                    .request(request.method.clone(), &url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .json(&body)
+            })
+            .await;
+        let response = match response {
+            Ok(v) => v,
+            Err(e) => return Err(Error::Transport { url, source: e }),
+        };
 
-            Ok(cache.clone())
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        match response.text().await {
+            Ok(body) => Ok(Response {
+                url: request.url,
+                status,
+                headers,
+                body,
+            }),
+            Err(e) => Err(Error::Transport { url, source: e }),
         }
     }
 }
 
+// Wake up just before the token leaves its refresh window, but never sooner
+// than `MIN_REFRESH_INTERVAL` from now
+fn refresh_at(token: &HydrolixToken, margin: Duration) -> Instant {
+    let wake_at = token
+        .expires_at
+        .checked_sub(token.refresh_margin(margin))
+        .unwrap_or_else(Instant::now);
+    wake_at.max(Instant::now() + MIN_REFRESH_INTERVAL)
+}
+
+// Handle to a background task that keeps a `HydrolixToken` fresh
+#[derive(Debug)]
+pub struct TokenRefresher {
+    receiver: watch::Receiver<HydrolixToken>,
+    handle: JoinHandle<()>,
+}
+
+impl TokenRefresher {
+    // The most recently refreshed token
+    pub fn token(&self) -> HydrolixToken {
+        self.receiver.borrow().clone()
+    }
+
+    // Receiver that is notified every time the token is renewed
+    pub fn subscribe(&self) -> watch::Receiver<HydrolixToken> {
+        self.receiver.clone()
+    }
+}
+
+impl Drop for TokenRefresher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::auth::HydrolixAuth;
     use crate::auth::HydrolixToken;
+    use crate::auth::TOKEN_CACHE;
+    use crate::auth::{refresh_at, DEFAULT_REFRESH_MARGIN};
+    use crate::error::Error;
+    use crate::http::Method;
     use crate::hydrolix;
+    use crate::mock::MockMethods;
+    use crate::retry::RetryPolicy;
+    use serde_json::json;
     use std::fs;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
 
     #[tokio::test]
     async fn test_token() {
@@ -212,6 +456,186 @@ mod tests {
         assert!(x.hits == 0);
     }
 
+    #[tokio::test]
+    async fn test_token_cache_per_cluster() {
+        let first = HydrolixAuth::new("cache-a.example.com", "user@example.com", "pw").await;
+        let second = HydrolixAuth::new("cache-b.example.com", "user@example.com", "pw").await;
+        let other_user = HydrolixAuth::new("cache-a.example.com", "other@example.com", "pw").await;
+
+        // Seed the cache so no login is attempted
+        {
+            let mut cache = TOKEN_CACHE.lock().await;
+            for (auth, value) in [(&first, "a"), (&second, "b"), (&other_user, "c")] {
+                let mut token = HydrolixToken::new();
+                token.base_url = auth.base_url.to_string();
+                token.value = value.to_string();
+                token.expires_at = Instant::now() + Duration::from_secs(600);
+                cache.insert(auth.cache_key(), token);
+            }
+        }
+
+        // Creating a new auth for a cluster must not reset the others
+        let _ = HydrolixAuth::new("cache-c.example.com", "user@example.com", "pw").await;
+
+        match first.clone().get_token().await {
+            Ok(v) => assert!(v.value == "a" && v.base_url == "cache-a.example.com"),
+            Err(e) => panic!("Failed to get token: {e}"),
+        }
+        match second.clone().get_token().await {
+            Ok(v) => assert!(v.value == "b" && v.base_url == "cache-b.example.com"),
+            Err(e) => panic!("Failed to get token: {e}"),
+        }
+        match other_user.clone().get_token().await {
+            Ok(v) => assert!(v.value == "c"),
+            Err(e) => panic!("Failed to get token: {e}"),
+        }
+        match first.clone().get_token().await {
+            Ok(v) => assert!(v.hits == 2),
+            Err(e) => panic!("Failed to get token: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_refresh_margin() {
+        let mut token = HydrolixToken::new();
+        token.expires_at = Instant::now() + Duration::from_secs(120);
+
+        assert!(token.is_fresh(Duration::from_secs(60)));
+        assert!(!token.is_fresh(Duration::from_secs(300)));
+
+        // An unreachable cluster proves the cache is bypassed inside the margin
+        let auth = HydrolixAuth::new("127.0.0.1:9", "margin@example.com", "pw")
+            .await
+            .with_retry_policy(RetryPolicy::none())
+            .with_refresh_margin(Duration::from_secs(300));
+        {
+            let mut cache = TOKEN_CACHE.lock().await;
+            cache.insert(auth.cache_key(), token.clone());
+        }
+        assert!(auth.clone().get_token().await.is_err());
+
+        let auth = auth.with_refresh_margin(Duration::from_secs(60));
+        assert!(auth.clone().get_token().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_short_lived_token() {
+        // `expires_in: 60` is inside the default 5 minute margin
+        let mut token = HydrolixToken::new();
+        token.lifetime = Some(Duration::from_secs(60));
+        token.expires_at = Instant::now() + Duration::from_secs(60);
+        assert!(token.refresh_margin(DEFAULT_REFRESH_MARGIN) == Duration::from_secs(30));
+        assert!(token.is_fresh(DEFAULT_REFRESH_MARGIN));
+
+        let wake_at = refresh_at(&token, DEFAULT_REFRESH_MARGIN);
+        assert!(wake_at > Instant::now() + Duration::from_secs(20));
+        assert!(wake_at <= token.expires_at - Duration::from_secs(30));
+
+        // The cached token is used instead of logging in on every call
+        let auth = HydrolixAuth::new("127.0.0.1:9", "short@example.com", "pw")
+            .await
+            .with_retry_policy(RetryPolicy::none());
+        {
+            let mut cache = TOKEN_CACHE.lock().await;
+            cache.insert(auth.cache_key(), token.clone());
+        }
+        assert!(auth.clone().get_token().await.is_ok());
+
+        // An almost expired token doesn't wake the refresher right away
+        token.lifetime = Some(Duration::from_secs(2));
+        token.expires_at = Instant::now() + Duration::from_secs(1);
+        assert!(!token.is_fresh(DEFAULT_REFRESH_MARGIN));
+        assert!(
+            refresh_at(&token, DEFAULT_REFRESH_MARGIN) >= Instant::now() + Duration::from_secs(9)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_error_redacts_password() {
+        let auth = HydrolixAuth::new("127.0.0.1:9", "redact@example.com", "hunter2")
+            .await
+            .with_retry_policy(RetryPolicy::none());
+        assert!(!format!("{auth:?}").contains("hunter2"));
+
+        let e = match auth.clone().get_token().await {
+            Ok(_) => panic!("Login to a closed port should fail"),
+            Err(Error::Auth(e)) => e,
+            Err(e) => panic!("Unexpected error {e}"),
+        };
+        assert!(e.username == "redact@example.com");
+        assert!(e.url == "https://127.0.0.1:9/config/v1/login");
+        assert!(!e.to_string().contains("hunter2"));
+        assert!(!format!("{e:?}").contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_refresher() {
+        let auth = HydrolixAuth::new("refresher.example.com", "user@example.com", "pw").await;
+        {
+            let mut cache = TOKEN_CACHE.lock().await;
+            let mut token = HydrolixToken::new();
+            token.value = "cached".to_string();
+            token.expires_at = Instant::now() + Duration::from_secs(3600);
+            cache.insert(auth.cache_key(), token);
+        }
+
+        let refresher = match auth.spawn_refresher().await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to start refresher: {e}"),
+        };
+        assert!(refresher.token().value == "cached");
+        assert!(refresher.subscribe().borrow().value == "cached");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresher_renews() {
+        let url = "https://renew.example.com/config/v1/login";
+        let login = |token: &str, expires_in: u64| {
+            json!({
+                "auth_token": {"access_token": token, "expires_in": expires_in},
+                "orgs": [],
+                "roles": [],
+                "email": "user@example.com",
+                "emailVerified": true
+            })
+            .to_string()
+        };
+        let methods = Arc::new(
+            MockMethods::default()
+                .with_response(Method::POST, url, 200, &login("first", 60))
+                .with_response(Method::POST, url, 200, &login("second", 1))
+                .with_response(Method::POST, url, 200, &login("third", 1)),
+        );
+        let auth = HydrolixAuth::new("renew.example.com", "user@example.com", "pw").await;
+
+        let start = Instant::now();
+        let refresher = match auth.spawn_refresher_helper(methods.clone()).await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to start refresher: {e}"),
+        };
+        assert!(refresher.token().value == "first");
+        let mut receiver = refresher.subscribe();
+
+        // The 5 minute margin is capped at half of the 60s lifetime
+        if let Err(e) = receiver.changed().await {
+            panic!("The refresher stopped: {e}");
+        }
+        assert!(receiver.borrow_and_update().value == "second");
+        assert!(start.elapsed() == Duration::from_secs(30));
+
+        // A token that is about to expire waits for the minimum interval
+        if let Err(e) = receiver.changed().await {
+            panic!("The refresher stopped: {e}");
+        }
+        assert!(refresher.token().value == "third");
+        assert!(start.elapsed() == Duration::from_secs(40));
+
+        let logins = methods.requests();
+        assert!(logins.len() == 3);
+        assert!(logins.iter().all(|v| v.url.as_str() == url));
+        assert!(logins[0].auth_token.as_str().is_empty());
+    }
+
     #[tokio::test]
     async fn test_get_token() {
         let file_path = "/tmp/fleet.secrets.toml";
@@ -0,0 +1,428 @@
+use once_cell::sync::Lazy;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Certificate, Client, Proxy};
+use tokio::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::http::{BearerToken, Request, Response, Url};
+use crate::retry::RetryPolicy;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_USER_AGENT: &str = concat!("hydrolix_helpers/", env!("CARGO_PKG_VERSION"));
+
+// Used by the free functions in `http`, `auth` and `dump`, so that even
+// callers that never build their own client share one connection pool.
+static SHARED_CLIENT: Lazy<HydrolixClient> = Lazy::new(HydrolixClient::new);
+
+// Owns the `reqwest::Client`s, one for streamed bodies, so connections and
+// TLS sessions are reused across every request made to a cluster.  Cloning
+// is cheap.
+#[derive(Debug, Clone)]
+pub struct HydrolixClient {
+    http_client: Client,
+    // Same settings, with the timeout between two reads instead of for the
+    // whole request, so a body read slowly on purpose isn't cut off
+    streaming_client: Client,
+    retry: RetryPolicy,
+}
+
+impl Default for HydrolixClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HydrolixClient {
+    pub fn new() -> HydrolixClient {
+        match HydrolixClient::builder().build() {
+            Ok(v) => v,
+            // The default builder sets no proxy or certificates, so only a
+            // broken TLS backend can get here
+            Err(e) => panic!("Failed to build the default HTTP client: {e}"),
+        }
+    }
+
+    pub fn builder() -> HydrolixClientBuilder {
+        HydrolixClientBuilder::default()
+    }
+
+    // Process wide client used when the caller does not pass one
+    pub fn shared() -> HydrolixClient {
+        SHARED_CLIENT.clone()
+    }
+
+    pub fn http_client(&self) -> &Client {
+        &self.http_client
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    // Same connection pool, different retry policy
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub async fn get_data(&self, url: &Url, auth_token: &BearerToken) -> Result<String> {
+        let response = self.send(&Request::get(url, auth_token)).await?;
+        Ok(response.error_for_status()?.body)
+    }
+
+    // Send any request and return the response whatever its status.  Only
+    // idempotent requests are retried, a failed POST could already have been
+    // applied by the cluster.
+    pub async fn send(&self, request: &Request) -> Result<Response> {
+        let response = self.execute(&self.http_client, request).await?;
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        match response.text().await {
+            Ok(body) => Ok(Response {
+                url: request.url.clone(),
+                status,
+                headers,
+                body,
+            }),
+            Err(e) => Err(Error::Transport {
+                url: request.url.to_string(),
+                source: e,
+            }),
+        }
+    }
+
+    // Same as `send`, without reading the body, so it can be streamed.  The
+    // timeout of the client applies between two reads of the body, not to
+    // the whole request, use a deadline such as `Query::with_deadline` to
+    // bound it.
+    pub async fn send_streaming(&self, request: &Request) -> Result<reqwest::Response> {
+        self.execute(&self.streaming_client, request).await
+    }
+
+    async fn execute(&self, client: &Client, request: &Request) -> Result<reqwest::Response> {
+        let build = || {
+            let mut builder = client
+                .request(request.method.clone(), request.url.as_str())
+                .header("accept", "application/json");
+            // No token, e.g. when a stream token is sent in a header instead
+            if !request.auth_token.as_str().is_empty() {
+                builder = builder.bearer_auth(request.auth_token.as_str());
+            }
+            builder = match (&request.body, &request.text) {
+                (Some(body), _) => builder.json(body),
+                (None, Some(text)) => builder
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(text.to_string()),
+                (None, None) => builder.header(CONTENT_TYPE, "application/json"),
+            };
+            builder.headers(request.headers.clone())
+        };
+
+        let response = if request.is_idempotent() {
+            self.retry.send(build).await
+        } else {
+            RetryPolicy::none().send(build).await
+        };
+
+        match response {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::Transport {
+                url: request.url.to_string(),
+                source: e,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HydrolixClientBuilder {
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    proxy: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    accept_invalid_certs: bool,
+    user_agent: String,
+    gzip: bool,
+    brotli: bool,
+    retry: RetryPolicy,
+}
+
+impl Default for HydrolixClientBuilder {
+    fn default() -> Self {
+        HydrolixClientBuilder {
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: None,
+            proxy: None,
+            root_certificates: vec![],
+            accept_invalid_certs: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            gzip: true,
+            brotli: true,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl HydrolixClientBuilder {
+    // Timeout for a whole request, from connecting until the body is read.
+    // For streamed bodies it is the longest wait between two reads.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    // Send every request through this proxy, e.g. `http://proxy.example.com:3128`
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    // Trust an extra PEM encoded root CA, for clusters with a private CA
+    pub fn add_root_certificate_pem(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    // Skip certificate validation.  Only meant for lab clusters.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    // Ask for gzip and brotli compressed responses and decompress them
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.gzip = enabled;
+        self.brotli = enabled;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn build(self) -> Result<HydrolixClient> {
+        let http_client = self.client_builder()?.timeout(self.timeout);
+        let streaming_client = self.client_builder()?.read_timeout(self.timeout);
+        Ok(HydrolixClient {
+            http_client: build_client(http_client)?,
+            streaming_client: build_client(streaming_client)?,
+            retry: self.retry,
+        })
+    }
+
+    // Everything but the timeout
+    fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = Client::builder()
+            .user_agent(self.user_agent.to_string())
+            .gzip(self.gzip)
+            .brotli(self.brotli)
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+
+        if let Some(v) = self.connect_timeout {
+            builder = builder.connect_timeout(v);
+        }
+
+        if let Some(url) = &self.proxy {
+            let proxy = match Proxy::all(url) {
+                Ok(v) => v,
+                Err(e) => return Err(Error::Config(format!("Invalid proxy {url}: {e}"))),
+            };
+            builder = builder.proxy(proxy);
+        }
+
+        for pem in &self.root_certificates {
+            let certificate = match Certificate::from_pem(pem) {
+                Ok(v) => v,
+                Err(e) => return Err(Error::Config(format!("Invalid root certificate: {e}"))),
+            };
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(builder)
+    }
+}
+
+fn build_client(builder: reqwest::ClientBuilder) -> Result<Client> {
+    match builder.build() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(Error::Config(format!("Failed to build HTTP client: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HydrolixClient;
+    use crate::error::Error;
+    use crate::http::{BearerToken, Http, Method, Methods, Request, Url};
+    use crate::retry::RetryPolicy;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_builder() {
+        let client = HydrolixClient::builder()
+            .timeout(Duration::from_secs(5))
+            .connect_timeout(Duration::from_secs(1))
+            .proxy("http://proxy.example.com:3128")
+            .accept_invalid_certs(true)
+            .user_agent("fleet-tool/1.0")
+            .compression(false)
+            .retry_policy(RetryPolicy::none())
+            .build();
+        match client {
+            Ok(v) => assert!(v.retry().max_attempts == 1),
+            Err(e) => panic!("Failed to build client: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_builder_rejects_bad_certificate() {
+        let client = HydrolixClient::builder()
+            .add_root_certificate_pem(b"not a certificate")
+            .build();
+        match client {
+            Ok(_) => panic!("A bogus certificate should be rejected"),
+            Err(Error::Config(_)) => (),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_and_reuse() {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to bind: {e}"),
+        };
+        let addr = match listener.local_addr() {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to get address: {e}"),
+        };
+
+        // A single connection serving two requests proves the pool is reused
+        let server = tokio::spawn(async move {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return vec![];
+            };
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                requests.push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n[]")
+                    .await;
+            }
+            requests
+        });
+
+        let client = match HydrolixClient::builder()
+            .user_agent("fleet-tool/1.0")
+            .build()
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to build client: {e}"),
+        };
+        let url = Url::new(format!("http://{addr}/config/v1/orgs"));
+        let token = BearerToken::new("token");
+        for _ in 0..2 {
+            match client.get_data(&url, &token).await {
+                Ok(v) => assert!(v == "[]"),
+                Err(e) => panic!("Failed to get data: {e}"),
+            }
+        }
+
+        let requests = match server.await {
+            Ok(v) => v,
+            Err(e) => panic!("Server failed: {e}"),
+        };
+        assert!(requests.len() == 2);
+        assert!(requests[0].contains("user-agent: fleet-tool/1.0"));
+        assert!(requests[1].contains("authorization: bearer token"));
+    }
+
+    // Serves a chunked body: `first`, then after `pause` the rest
+    async fn serve_chunks(first: &'static str, pause: Duration, rest: &'static str) -> String {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to bind: {e}"),
+        };
+        let addr = match listener.local_addr() {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to get address: {e}"),
+        };
+        tokio::spawn(async move {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            let head = format!(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{first}\r\n",
+                first.len()
+            );
+            let _ = socket.write_all(head.as_bytes()).await;
+            tokio::time::sleep(pause).await;
+            let tail = format!("{:x}\r\n{rest}\r\n0\r\n\r\n", rest.len());
+            let _ = socket.write_all(tail.as_bytes()).await;
+        });
+        format!("http://{addr}/query")
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_consumer() {
+        let client = match HydrolixClient::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to build client: {e}"),
+        };
+        let methods = Http { client };
+        let request = |url: &str| {
+            Request::new(Method::POST, &Url::new(url), &BearerToken::new("token"))
+                .with_text("SELECT 1")
+        };
+
+        // Rows consumed slower than the client timeout are all read
+        let url = serve_chunks("a,1\n", Duration::from_millis(50), "b,2\n").await;
+        let mut body = match methods.send_streaming(request(&url)).await {
+            Ok(v) => v.body,
+            Err(e) => panic!("Failed to send: {e}"),
+        };
+        let mut read = vec![];
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(v) => read.extend(v),
+                Err(e) => panic!("The slow consumer was cut off: {e}"),
+            }
+            tokio::time::sleep(Duration::from_millis(400)).await;
+        }
+        assert!(read == b"a,1\nb,2\n");
+
+        // A server that stops sending still times out
+        let url = serve_chunks("a,1\n", Duration::from_secs(5), "b,2\n").await;
+        let mut body = match methods.send_streaming(request(&url)).await {
+            Ok(v) => v.body,
+            Err(e) => panic!("Failed to send: {e}"),
+        };
+        assert!(matches!(body.next().await, Some(Ok(_))));
+        match body.next().await {
+            Some(Err(e)) => assert!(e.is_timeout(), "{e}"),
+            _ => panic!("A stalled body should time out"),
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use crate::apply::{self, Plan};
+use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::dump::{self, DumpOptions, Filter};
+use crate::error::{Error, Result};
+use crate::http::{self, BearerToken, Http, Methods, Url};
+use crate::hydrolix::cluster;
+use crate::hydrolix::org;
+use crate::hydrolix::project;
+use crate::hydrolix::storage;
+
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    // Name or UUID of the org on the target, the source org's name by default
+    pub target_org: Option<String>,
+    // New name for the project, the source name by default
+    pub name: Option<String>,
+    // Plan only, don't create anything
+    pub dry_run: bool,
+}
+
+impl CloneOptions {
+    pub fn with_target_org(mut self, org: &str) -> Self {
+        self.target_org = Some(org.to_string());
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+}
+
+// What cloning creates, and what already exists on the target under the same
+// name.  Nothing is created while there are conflicts.
+#[derive(Debug, Clone)]
+pub struct ClonePlan {
+    pub plan: Plan,
+    pub conflicts: Vec<String>,
+}
+
+// Plan the creation of `source` and everything in it in an org of `target`.
+// `target` only needs the org's projects and storages, their tables are not
+// looked at.  Tables are pointed at the target's storages of the same name.
+pub fn plan_clone(
+    source: &project::Project,
+    source_org: &org::Org,
+    target: &cluster::Cluster,
+    options: &CloneOptions,
+) -> Result<ClonePlan> {
+    let org_name = options.target_org.as_deref().unwrap_or(&source_org.name);
+    let Some(target_org) = target
+        .orgs
+        .iter()
+        .flatten()
+        .find(|v| v.name == org_name || v.uuid == org_name)
+    else {
+        return Err(Error::Config(format!(
+            "Org {org_name} does not exist on {}",
+            target.base_url
+        )));
+    };
+
+    let mut copy = source.clone();
+    if let Some(name) = &options.name {
+        copy.name = name.to_string();
+    }
+    // Never match the source by UUID when cloning within one cluster
+    copy.uuid = String::new();
+    for t in copy.tables.iter_mut().flatten() {
+        if let Some(storage_map) = &mut t.settings.storage_map {
+            let path = format!("{}/{}", copy.name, t.name);
+            apply::map_storages(storage_map, |uuid| {
+                target_storage(source_org, target_org, uuid, &path, &target.base_url)
+            })?;
+        }
+    }
+
+    let conflicts: Vec<String> = target_org
+        .projects
+        .iter()
+        .flatten()
+        .filter(|v| v.name == copy.name)
+        .map(|v| format!("project {}/{}", target_org.name, v.name))
+        .collect();
+
+    // The target as it is, plus the copy.  Planning against it yields only
+    // the creates for the copy.
+    let mut desired_org = target_org.clone();
+    desired_org.projects.get_or_insert_with(Vec::new).push(copy);
+    let live = cluster::Cluster {
+        base_url: target.base_url.to_string(),
+        orgs: Some(vec![target_org.clone()]),
+    };
+    let desired = cluster::Cluster {
+        base_url: target.base_url.to_string(),
+        orgs: Some(vec![desired_org]),
+    };
+    let plan = apply::plan(&desired, &live)?.without_deletes();
+
+    Ok(ClonePlan { plan, conflicts })
+}
+
+// Copy a project with its functions, tables and transforms from one cluster
+// or org to another.  Fails without creating anything when the project
+// already exists on the target.
+pub async fn clone_project(
+    source_client: &HydrolixClient,
+    source_token: &HydrolixToken,
+    source_org: &str,
+    project: &str,
+    target_client: &HydrolixClient,
+    target_token: &HydrolixToken,
+    options: &CloneOptions,
+) -> Result<ClonePlan> {
+    let source = Http {
+        client: source_client.clone(),
+    };
+    let target = Http {
+        client: target_client.clone(),
+    };
+    clone_helper(
+        &source,
+        source_token,
+        source_org,
+        project,
+        &target,
+        target_token,
+        options,
+    )
+    .await
+}
+
+pub async fn clone_helper(
+    source: &impl Methods,
+    source_token: &HydrolixToken,
+    source_org: &str,
+    project: &str,
+    target: &impl Methods,
+    target_token: &HydrolixToken,
+    options: &CloneOptions,
+) -> Result<ClonePlan> {
+    // Storages too, to find the ones of the same name on the target
+    let dump_options = DumpOptions::default()
+        .with_orgs(Filter::default().include(source_org))
+        .with_projects(Filter::default().include(project));
+    let (cluster, _) = dump::dump_helper(source, source_token, &dump_options).await?;
+    let found = cluster.orgs.iter().flatten().find_map(|o| {
+        o.projects
+            .iter()
+            .flatten()
+            .find(|p| p.name == project || p.uuid == project)
+            .map(|p| (o, p))
+    });
+    let Some((source_org, source_project)) = found else {
+        return Err(Error::Config(format!(
+            "Project {project} does not exist in org {source_org} on {}",
+            source_token.base_url
+        )));
+    };
+
+    let live = target_org(
+        target,
+        target_token,
+        options.target_org.as_deref().unwrap_or(&source_org.name),
+    )
+    .await?;
+    let result = plan_clone(source_project, source_org, &live, options)?;
+    if !result.conflicts.is_empty() {
+        return Err(Error::Config(format!(
+            "Already exists on {}: {}",
+            target_token.base_url,
+            result.conflicts.join(", ")
+        )));
+    }
+
+    if !options.dry_run {
+        apply::apply_helper(target, target_token, &result.plan).await?;
+    }
+    Ok(result)
+}
+
+// The UUID on the target of the storage `uuid` of the source, by name
+fn target_storage(
+    source_org: &org::Org,
+    target_org: &org::Org,
+    uuid: &str,
+    path: &str,
+    base_url: &str,
+) -> Result<String> {
+    let Some(source) = source_org
+        .storages
+        .iter()
+        .flatten()
+        .find(|v| v.uuid == uuid)
+    else {
+        return Err(Error::Config(format!(
+            "Table {path} uses storage {uuid}, which is not in org {}",
+            source_org.name
+        )));
+    };
+    match target_org
+        .storages
+        .iter()
+        .flatten()
+        .find(|v| v.name == source.name)
+    {
+        Some(v) => Ok(v.uuid.to_string()),
+        None => Err(Error::Config(format!(
+            "Table {path} uses storage {}, which does not exist in org {} on {base_url}",
+            source.name, target_org.name
+        ))),
+    }
+}
+
+// The target org with its projects, enough to find name conflicts, and its
+// storages
+async fn target_org(
+    methods: &impl Methods,
+    auth_token: &HydrolixToken,
+    org_name: &str,
+) -> Result<cluster::Cluster> {
+    let Some(o) = auth_token
+        .org_list
+        .iter()
+        .find(|v| v.name == org_name || v.uuid == org_name)
+    else {
+        return Err(Error::Config(format!(
+            "Org {org_name} does not exist on {}",
+            auth_token.base_url
+        )));
+    };
+
+    let url = Url::new(format!(
+        "https://{}/config/v1/orgs/{}/projects",
+        auth_token.base_url, o.uuid
+    ));
+    let bearer = BearerToken::from(auth_token);
+    let projects: Vec<project::Project> =
+        http::get_paginated_helper(&url, &bearer, methods).await?;
+    let url = Url::new(format!(
+        "https://{}/config/v1/orgs/{}/storages",
+        auth_token.base_url, o.uuid
+    ));
+    let storages: Vec<storage::Storage> =
+        http::get_paginated_helper(&url, &bearer, methods).await?;
+
+    Ok(cluster::Cluster {
+        base_url: auth_token.base_url.to_string(),
+        orgs: Some(vec![org::Org {
+            uuid: o.uuid.to_string(),
+            name: o.name.to_string(),
+            cloud: o.cloud.to_string(),
+            kubernetes: o.kubernetes,
+            projects: Some(projects),
+            storages: Some(storages),
+        }]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clone_helper, CloneOptions};
+    use crate::apply::Action;
+    use crate::http::Method;
+    use crate::mock::{self, fixtures, MockMethods};
+    use serde_json::json;
+
+    // A second cluster `h` with only the project `p1` and the storage `s1`
+    // in `o1`
+    fn target() -> MockMethods {
+        MockMethods::default()
+            .with_page(
+                "https://h/config/v1/orgs/o1/projects?page=1",
+                &fixtures::list(&[fixtures::project("o1", "p1", "prod-p1")]),
+            )
+            .with_page(
+                "https://h/config/v1/orgs/o1/storages?page=1",
+                &fixtures::list(&[fixtures::storage("o1", "s1", "prod-s1")]),
+            )
+    }
+
+    // The mock cluster with table `t1` of `p1` stored on `storage`
+    fn source(storage: &str) -> MockMethods {
+        let mut t1 = fixtures::table("p1", "t1", "t1");
+        t1["settings"]["storage_map"] = json!({
+            "default_storage_id": storage,
+            "column_name": "tier",
+            "column_value_mapping": {storage: ["cold"]},
+        });
+        mock::cluster().with_override(
+            Method::GET,
+            "https://h/config/v1/orgs/o1/projects/p1/tables?page=1",
+            200,
+            &fixtures::list(&[t1, fixtures::table("p1", "t2", "t2")]),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_clone_conflict() {
+        let target = target();
+        let result = clone_helper(
+            &mock::cluster(),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &CloneOptions::default(),
+        )
+        .await;
+        match result {
+            Ok(_) => panic!("p1 already exists on the target"),
+            Err(e) => assert!(e.to_string().contains("project org1/p1")),
+        }
+        assert!(target.writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clone_renamed() {
+        let base = "https://h/config/v1/orgs/o1";
+        let created = |uuid: &str| json!({ "uuid": uuid }).to_string();
+        let target = target()
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/"),
+                201,
+                &created("new"),
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new/functions/"),
+                201,
+                "{}",
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new/tables/"),
+                201,
+                &created("nt1"),
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new/tables/"),
+                201,
+                &created("nt2"),
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new/tables/nt1/transforms/"),
+                201,
+                "{}",
+            )
+            .with_response(
+                Method::POST,
+                &format!("{base}/projects/new/tables/nt2/transforms/"),
+                201,
+                "{}",
+            );
+
+        let options = CloneOptions::default().with_name("p1_copy");
+        let result = match clone_helper(
+            &mock::cluster(),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &options,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to clone: {e}"),
+        };
+        assert!(result.conflicts.is_empty());
+        assert!(result
+            .plan
+            .operations
+            .iter()
+            .all(|v| v.action == Action::Create));
+
+        let writes = target.writes();
+        assert!(
+            writes
+                == vec![
+                    format!("POST {base}/projects/"),
+                    format!("POST {base}/projects/new/functions/"),
+                    format!("POST {base}/projects/new/tables/"),
+                    format!("POST {base}/projects/new/tables/nt1/transforms/"),
+                    format!("POST {base}/projects/new/tables/"),
+                    format!("POST {base}/projects/new/tables/nt2/transforms/"),
+                ]
+        );
+
+        let requests = target.requests();
+        let posted: Vec<_> = requests.iter().filter_map(|v| v.body.as_ref()).collect();
+        assert!(posted[0]["name"] == "p1_copy");
+        assert!(posted[0]["org"] == "o1");
+        assert!(posted[3]["table"] == "nt1");
+    }
+
+    #[tokio::test]
+    async fn test_clone_storages() {
+        let target = target();
+        let options = CloneOptions::default().with_name("p1_copy").dry_run();
+        let result = match clone_helper(
+            &source("s1"),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &options,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to plan: {e}"),
+        };
+        // The table is stored on the target's `s1`
+        let Some(body) = result.plan.operations.iter().find_map(|v| {
+            v.body
+                .as_ref()
+                .filter(|v| v["name"] == "t1" && v.get("primary_key").is_some())
+        }) else {
+            panic!("No table t1 in the plan");
+        };
+        let storage_map = &body["settings"]["storage_map"];
+        assert!(storage_map["default_storage_id"] == "prod-s1");
+        assert!(storage_map["column_value_mapping"]["prod-s1"] == json!(["cold"]));
+
+        // A storage the source org doesn't have
+        match clone_helper(
+            &source("gone"),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &options,
+        )
+        .await
+        {
+            Ok(_) => panic!("The storage is unknown"),
+            Err(e) => assert!(e.to_string().contains("storage gone")),
+        }
+
+        // A storage the target org doesn't have
+        let target = MockMethods::default()
+            .with_page("https://h/config/v1/orgs/o1/projects?page=1", "[]")
+            .with_page("https://h/config/v1/orgs/o1/storages?page=1", "[]");
+        match clone_helper(
+            &source("s1"),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &options,
+        )
+        .await
+        {
+            Ok(_) => panic!("s1 does not exist on the target"),
+            Err(e) => assert!(e.to_string().contains("storage s1, which does not exist")),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clone_dry_run() {
+        let target = target();
+        let options = CloneOptions::default().with_name("p1_copy").dry_run();
+        let result = clone_helper(
+            &mock::cluster(),
+            &mock::token(),
+            "org1",
+            "p1",
+            &target,
+            &mock::token(),
+            &options,
+        )
+        .await;
+        match result {
+            Ok(v) => assert!(v.plan.operations.len() == 6),
+            Err(e) => panic!("Failed to plan: {e}"),
+        }
+        assert!(target.writes().is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+
+use crate::error::Result;
+use crate::export;
+use crate::hydrolix::cluster;
+
+// Fields that always differ between clusters and say nothing about the
+// configuration.  `org`, `project` and `table` are the UUIDs of the parent.
+const IGNORED_FIELDS: [&str; 7] = [
+    "uuid", "created", "modified", "url", "org", "project", "table",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+// One field that differs, e.g. `settings.stream.hot_data_max_age_minutes`.
+// `before` is missing when the field only exists in `b`, `after` when it only
+// exists in `a`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+// A resource that differs, e.g. the table `org1/web/logs`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResourceDiff {
+    pub kind: DiffKind,
+    pub resource: String,
+    pub path: String,
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterDiff {
+    pub a: String,
+    pub b: String,
+    pub changes: Vec<ResourceDiff>,
+}
+
+impl ClusterDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        export::to_pretty_json(self, &[])
+    }
+}
+
+impl fmt::Display for ClusterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "No differences between {} and {}", self.a, self.b);
+        }
+        writeln!(f, "--- {}\n+++ {}", self.a, self.b)?;
+        for c in &self.changes {
+            let kind = match c.kind {
+                DiffKind::Added => "added",
+                DiffKind::Removed => "removed",
+                DiffKind::Modified => "modified",
+            };
+            writeln!(f, "{kind} {} {}", c.resource, c.path)?;
+            for v in &c.fields {
+                writeln!(f, "  {v}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `field: before -> after`, as shown by diffs and plans
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.field,
+            show(self.before.as_ref()),
+            show(self.after.as_ref())
+        )
+    }
+}
+
+fn show(value: Option<&Value>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => "(missing)".to_string(),
+    }
+}
+
+// Compare two dumps, e.g. staging against prod.  Resources are matched by
+// UUID and, since UUIDs differ between clusters, then by name.
+pub fn diff(a: &cluster::Cluster, b: &cluster::Cluster) -> ClusterDiff {
+    let mut changes = vec![];
+
+    let orgs_a = a.orgs.as_deref().unwrap_or_default();
+    let orgs_b = b.orgs.as_deref().unwrap_or_default();
+    for (x, y) in pair(orgs_a, orgs_b, |v| (&v.uuid, &v.name)) {
+        let name = either(x, y, |v| &v.name);
+        compare(&mut changes, "org", name, x, y, &["projects", "storages"]);
+        let (Some(x), Some(y)) = (x, y) else {
+            continue;
+        };
+
+        let storages_a = x.storages.as_deref().unwrap_or_default();
+        let storages_b = y.storages.as_deref().unwrap_or_default();
+        for (s, t) in pair(storages_a, storages_b, |v| (&v.uuid, &v.name)) {
+            let path = format!("{name}/{}", either(s, t, |v| &v.name));
+            compare(&mut changes, "storage", &path, s, t, &[]);
+        }
+
+        let projects_a = x.projects.as_deref().unwrap_or_default();
+        let projects_b = y.projects.as_deref().unwrap_or_default();
+        for (p, q) in pair(projects_a, projects_b, |v| (&v.uuid, &v.name)) {
+            let project_path = format!("{name}/{}", either(p, q, |v| &v.name));
+            compare(
+                &mut changes,
+                "project",
+                &project_path,
+                p,
+                q,
+                &["tables", "functions"],
+            );
+            let (Some(p), Some(q)) = (p, q) else {
+                continue;
+            };
+
+            let functions_a = p.functions.as_deref().unwrap_or_default();
+            let functions_b = q.functions.as_deref().unwrap_or_default();
+            for (s, t) in pair(functions_a, functions_b, |v| (&v.uuid, &v.name)) {
+                let path = format!("{project_path}/{}", either(s, t, |v| &v.name));
+                compare(&mut changes, "function", &path, s, t, &[]);
+            }
+
+            let tables_a = p.tables.as_deref().unwrap_or_default();
+            let tables_b = q.tables.as_deref().unwrap_or_default();
+            for (s, t) in pair(tables_a, tables_b, |v| (&v.uuid, &v.name)) {
+                let table_path = format!("{project_path}/{}", either(s, t, |v| &v.name));
+                compare(&mut changes, "table", &table_path, s, t, &["transforms"]);
+                let (Some(s), Some(t)) = (s, t) else {
+                    continue;
+                };
+
+                let transforms_a = s.transforms.as_deref().unwrap_or_default();
+                let transforms_b = t.transforms.as_deref().unwrap_or_default();
+                for (u, w) in pair(transforms_a, transforms_b, |v| (&v.uuid, &v.name)) {
+                    let path = format!("{table_path}/{}", either(u, w, |v| &v.name));
+                    compare(&mut changes, "transform", &path, u, w, &[]);
+                }
+            }
+        }
+    }
+
+    ClusterDiff {
+        a: a.base_url.to_string(),
+        b: b.base_url.to_string(),
+        changes,
+    }
+}
+
+// Pair up the items of two lists, first by UUID then by name.  Items of `a`
+// come first in their order, followed by the items only found in `b`.
+pub(crate) fn pair<'a, T>(
+    a: &'a [T],
+    b: &'a [T],
+    key: impl Fn(&T) -> (&String, &String),
+) -> Vec<(Option<&'a T>, Option<&'a T>)> {
+    let mut used = vec![false; b.len()];
+    let mut matched: Vec<Option<usize>> = a
+        .iter()
+        .map(|x| {
+            let found = b.iter().position(|y| key(x).0 == key(y).0);
+            if let Some(i) = found {
+                used[i] = true;
+            }
+            found
+        })
+        .collect();
+
+    for (i, x) in a.iter().enumerate() {
+        if matched[i].is_some() {
+            continue;
+        }
+        let found = (0..b.len()).find(|j| !used[*j] && key(x).1 == key(&b[*j]).1);
+        if let Some(j) = found {
+            used[j] = true;
+            matched[i] = Some(j);
+        }
+    }
+
+    let mut pairs: Vec<(Option<&T>, Option<&T>)> = a
+        .iter()
+        .zip(matched)
+        .map(|(x, j)| (Some(x), j.map(|j| &b[j])))
+        .collect();
+    for (j, y) in b.iter().enumerate() {
+        if !used[j] {
+            pairs.push((None, Some(y)));
+        }
+    }
+    pairs
+}
+
+pub(crate) fn either<'a, T>(
+    a: Option<&'a T>,
+    b: Option<&'a T>,
+    name: impl Fn(&T) -> &String,
+) -> &'a str {
+    match (a, b) {
+        (Some(v), _) | (None, Some(v)) => name(v),
+        (None, None) => "",
+    }
+}
+
+fn compare<T: Serialize>(
+    changes: &mut Vec<ResourceDiff>,
+    resource: &str,
+    path: &str,
+    a: Option<&T>,
+    b: Option<&T>,
+    children: &[&str],
+) {
+    let (kind, fields) = match (a, b) {
+        (Some(x), Some(y)) => {
+            let mut fields = vec![];
+            diff_value(
+                &mut fields,
+                "",
+                &to_value(x, children),
+                &to_value(y, children),
+            );
+            if fields.is_empty() {
+                return;
+            }
+            (DiffKind::Modified, fields)
+        }
+        (Some(_), None) => (DiffKind::Removed, vec![]),
+        (None, Some(_)) => (DiffKind::Added, vec![]),
+        (None, None) => return,
+    };
+    changes.push(ResourceDiff {
+        kind,
+        resource: resource.to_string(),
+        path: path.to_string(),
+        fields,
+    });
+}
+
+pub(crate) fn to_value<T: Serialize>(value: &T, children: &[&str]) -> Value {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    if let Value::Object(map) = &mut value {
+        for key in children.iter().chain(IGNORED_FIELDS.iter()) {
+            map.remove(*key);
+        }
+    }
+    value
+}
+
+// Walk both values and record every leaf that differs.  Lists of named
+// objects, like transform columns, are matched by name so that inserting a
+// column does not show up as every following column changing.
+pub(crate) fn diff_value(fields: &mut Vec<FieldChange>, field: &str, a: &Value, b: &Value) {
+    if a == b {
+        return;
+    }
+    match (a, b) {
+        (Value::Object(x), Value::Object(y)) => diff_map(fields, field, x, y),
+        (Value::Array(x), Value::Array(y)) => match (by_name(x), by_name(y)) {
+            (Some(x), Some(y)) => diff_map(fields, field, &x, &y),
+            _ if x.len() == y.len() => {
+                for (i, (s, t)) in x.iter().zip(y).enumerate() {
+                    diff_value(fields, &format!("{field}[{i}]"), s, t);
+                }
+            }
+            _ => fields.push(FieldChange {
+                field: field.to_string(),
+                before: Some(a.clone()),
+                after: Some(b.clone()),
+            }),
+        },
+        _ => fields.push(FieldChange {
+            field: field.to_string(),
+            before: Some(a.clone()),
+            after: Some(b.clone()),
+        }),
+    }
+}
+
+fn diff_map(
+    fields: &mut Vec<FieldChange>,
+    field: &str,
+    a: &Map<String, Value>,
+    b: &Map<String, Value>,
+) {
+    let join = |key: &str| {
+        if field.is_empty() {
+            key.to_string()
+        } else {
+            format!("{field}.{key}")
+        }
+    };
+    for (key, x) in a {
+        match b.get(key) {
+            Some(y) => diff_value(fields, &join(key), x, y),
+            None => fields.push(FieldChange {
+                field: join(key),
+                before: Some(x.clone()),
+                after: None,
+            }),
+        }
+    }
+    for (key, y) in b {
+        if !a.contains_key(key) {
+            fields.push(FieldChange {
+                field: join(key),
+                before: None,
+                after: Some(y.clone()),
+            });
+        }
+    }
+}
+
+// `[{"name": "ts", ...}]` as `{"ts": {...}}`, when every name is unique
+fn by_name(items: &[Value]) -> Option<Map<String, Value>> {
+    let mut map = Map::new();
+    for v in items {
+        let name = v.get("name")?.as_str()?;
+        if map.insert(name.to_string(), v.clone()).is_some() {
+            return None;
+        }
+    }
+    Some(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, DiffKind};
+    use crate::dump::{dump_helper, DumpOptions};
+    use crate::hydrolix::cluster::Cluster;
+    use crate::mock;
+    use serde_json::{json, Value};
+
+    async fn mock_cluster() -> Cluster {
+        match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+            Ok((v, _)) => *v,
+            Err(e) => panic!("Failed to dump: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_cluster() {
+        let a = mock_cluster().await;
+        let result = diff(&a, &a);
+        assert!(result.is_empty());
+        assert!(result.to_string() == "No differences between h and h\n");
+    }
+
+    #[tokio::test]
+    async fn test_drift() {
+        let a = mock_cluster().await;
+        let mut b = a.clone();
+        b.base_url = "prod".to_string();
+
+        let Some(orgs) = &mut b.orgs else {
+            panic!("No orgs");
+        };
+        let Some(projects) = &mut orgs[0].projects else {
+            panic!("No projects");
+        };
+        // Another cluster, other UUIDs: matched by name
+        for p in projects.iter_mut() {
+            p.uuid = format!("prod-{}", p.uuid);
+        }
+        projects.remove(1);
+        let Some(tables) = &mut projects[0].tables else {
+            panic!("No tables");
+        };
+        tables[0].settings.stream.hot_data_max_age_minutes += 1;
+        let Some(transforms) = &mut tables[1].transforms else {
+            panic!("No transforms");
+        };
+        let mut extra = transforms[0].clone();
+        extra.uuid = "new".to_string();
+        extra.name = "extra".to_string();
+        transforms.push(extra);
+        if let Some(c) = transforms[0].settings.output_columns.first_mut() {
+            c.datatype.data_type = "uint64".to_string();
+        }
+
+        let result = diff(&a, &b);
+        let summary: Vec<(DiffKind, &str, &str)> = result
+            .changes
+            .iter()
+            .map(|v| (v.kind, v.resource.as_str(), v.path.as_str()))
+            .collect();
+        assert!(
+            summary
+                == vec![
+                    (DiffKind::Modified, "table", "org1/p1/t1"),
+                    (DiffKind::Modified, "transform", "org1/p1/t2/t2_transform"),
+                    (DiffKind::Added, "transform", "org1/p1/t2/extra"),
+                    (DiffKind::Removed, "project", "org1/p2"),
+                ]
+        );
+
+        let table = &result.changes[0].fields;
+        assert!(table.len() == 1);
+        assert!(table[0].field == "settings.stream.hot_data_max_age_minutes");
+
+        let transform = &result.changes[1].fields;
+        assert!(transform.len() == 1);
+        assert!(transform[0].field == "settings.output_columns.timestamp.datatype.type");
+        assert!(transform[0].after == Some(json!("uint64")));
+
+        let text = result.to_string();
+        assert!(text.starts_with("--- h\n+++ prod\n"));
+        assert!(text.contains("removed project org1/p2\n"));
+        assert!(text.contains(
+            "  settings.output_columns.timestamp.datatype.type: \"datetime\" -> \"uint64\"\n"
+        ));
+
+        let parsed: Value = match result.to_json() {
+            Ok(v) => match serde_json::from_str(&v) {
+                Ok(v) => v,
+                Err(e) => panic!("Invalid JSON: {e}"),
+            },
+            Err(e) => panic!("Failed to render: {e}"),
+        };
+        assert!(parsed["changes"][3]["kind"] == "removed");
+    }
+}
@@ -1,7 +1,20 @@
-use serde_json;
-
+use futures::future::try_join;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use glob::Pattern;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Semaphore;
+use tokio::time::{Duration, Instant};
+
+use crate::auth;
 use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::error::Result;
 use crate::http;
+use crate::http::{BearerToken, Http, Methods, Url};
 use crate::hydrolix::cluster;
 use crate::hydrolix::function;
 use crate::hydrolix::org;
@@ -9,188 +22,595 @@ use crate::hydrolix::project;
 use crate::hydrolix::storage;
 use crate::hydrolix::table;
 use crate::hydrolix::transform;
+use crate::retry::RetryPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailureMode {
+    // Stop at the first failed request and return its error
+    #[default]
+    FailFast,
+    // Keep going, leave out what could not be fetched and record it in the `DumpReport`
+    BestEffort,
+}
+
+// Selects resources by exact name, UUID or glob pattern such as `staging_*`.
+// An empty include list selects everything, and excludes win over includes.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub include: Vec<FilterPattern>,
+    pub exclude: Vec<FilterPattern>,
+}
+
+impl Filter {
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(FilterPattern::new(pattern));
+        self
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(FilterPattern::new(pattern));
+        self
+    }
+
+    pub fn matches(&self, name: &str, uuid: &str) -> bool {
+        if self.exclude.iter().any(|v| v.matches(name, uuid)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|v| v.matches(name, uuid))
+    }
+}
+
+// One include or exclude of a `Filter`, the glob is compiled once.  Text that
+// isn't a valid glob only matches exactly.
+#[derive(Debug, Clone)]
+pub struct FilterPattern {
+    pub text: String,
+    glob: Option<Pattern>,
+}
+
+impl FilterPattern {
+    pub fn new(text: &str) -> FilterPattern {
+        FilterPattern {
+            text: text.to_string(),
+            glob: Pattern::new(text).ok(),
+        }
+    }
+
+    pub fn matches(&self, name: &str, uuid: &str) -> bool {
+        if self.text == name || self.text == uuid {
+            return true;
+        }
+        match &self.glob {
+            Some(v) => v.matches(name),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+    // Maximum number of API requests in flight at once.  1 walks the
+    // cluster strictly one request after another.
+    pub concurrency: usize,
+    pub failure_mode: FailureMode,
+    pub orgs: Filter,
+    pub projects: Filter,
+    pub tables: Filter,
+    // When off, the API calls for that kind of resource are never made
+    pub storages: bool,
+    pub functions: bool,
+    pub transforms: bool,
+    // Told about the progress of the dump
+    pub observer: Option<Arc<dyn DumpObserver>>,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        DumpOptions {
+            concurrency: 1,
+            failure_mode: FailureMode::FailFast,
+            orgs: Filter::default(),
+            projects: Filter::default(),
+            tables: Filter::default(),
+            storages: true,
+            functions: true,
+            transforms: true,
+            observer: None,
+        }
+    }
+}
+
+impl DumpOptions {
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_failure_mode(mut self, failure_mode: FailureMode) -> Self {
+        self.failure_mode = failure_mode;
+        self
+    }
+
+    pub fn with_orgs(mut self, filter: Filter) -> Self {
+        self.orgs = filter;
+        self
+    }
+
+    pub fn with_projects(mut self, filter: Filter) -> Self {
+        self.projects = filter;
+        self
+    }
+
+    pub fn with_tables(mut self, filter: Filter) -> Self {
+        self.tables = filter;
+        self
+    }
+
+    pub fn without_storages(mut self) -> Self {
+        self.storages = false;
+        self
+    }
+
+    pub fn without_functions(mut self) -> Self {
+        self.functions = false;
+        self
+    }
+
+    pub fn without_transforms(mut self) -> Self {
+        self.transforms = false;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: impl DumpObserver + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+}
+
+// Progress of a dump, for progress bars and metrics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum DumpEvent {
+    OrgStarted {
+        org: String,
+    },
+    // The project's functions and tables were listed
+    ProjectFetched {
+        org: String,
+        project: String,
+        functions: usize,
+        tables: usize,
+    },
+    TransformsFetched {
+        org: String,
+        project: String,
+        table: String,
+        transforms: usize,
+    },
+    RequestRetried {
+        url: String,
+        retry: u32,
+        delay: Duration,
+        reason: String,
+    },
+    // Only sent when the dump succeeds
+    Finished {
+        orgs: usize,
+        storages: usize,
+        projects: usize,
+        functions: usize,
+        tables: usize,
+        transforms: usize,
+        failures: usize,
+        elapsed: Duration,
+    },
+}
+
+// Called from the tasks of the dump, so it must be cheap and must not block.
+// Closures and the sending half of a tokio channel are observers.
+pub trait DumpObserver: Send + Sync {
+    fn on_event(&self, event: &DumpEvent);
+}
+
+impl<F: Fn(&DumpEvent) + Send + Sync> DumpObserver for F {
+    fn on_event(&self, event: &DumpEvent) {
+        self(event)
+    }
+}
+
+// Events are dropped once the receiver is gone
+impl DumpObserver for UnboundedSender<DumpEvent> {
+    fn on_event(&self, event: &DumpEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+impl fmt::Debug for dyn DumpObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DumpObserver")
+    }
+}
+
+// A list that could not be fetched during a best-effort dump
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DumpFailure {
+    pub org: String,
+    pub project: Option<String>,
+    pub table: Option<String>,
+    // The kind of resource being listed, e.g. `transforms`
+    pub resource: String,
+    pub url: String,
+    pub status: Option<u16>,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DumpReport {
+    pub failures: Vec<DumpFailure>,
+}
+
+impl DumpReport {
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub async fn dump(auth_token: &HydrolixToken) -> Result<Box<cluster::Cluster>> {
+    dump_with_client(&HydrolixClient::shared(), auth_token).await
+}
+
+pub async fn dump_with_client(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+) -> Result<Box<cluster::Cluster>> {
+    let (cluster, _) = dump_with_options(client, auth_token, &DumpOptions::default()).await?;
+    Ok(cluster)
+}
+
+// Fetches per-project functions and tables, and per-table transforms, in
+// parallel.  The result is identical to the sequential dump.
+pub async fn dump_concurrent(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    concurrency: usize,
+) -> Result<Box<cluster::Cluster>> {
+    let options = DumpOptions::default().with_concurrency(concurrency);
+    let (cluster, _) = dump_with_options(client, auth_token, &options).await?;
+    Ok(cluster)
+}
+
+// With `FailureMode::BestEffort` the report lists everything that was left
+// out.  With `FailureMode::FailFast` it is always empty.
+pub async fn dump_with_options(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    options: &DumpOptions,
+) -> Result<(Box<cluster::Cluster>, DumpReport)> {
+    let mut client = client.clone();
+    if let Some(observer) = &options.observer {
+        let retry = observe_retries(client.retry(), observer);
+        client = client.with_retry_policy(retry);
+    }
+    let methods = Http { client };
+    dump_helper(&methods, auth_token, options).await
+}
+
+// Report retries to the observer too, after any hook already on the policy
+fn observe_retries(retry: &RetryPolicy, observer: &Arc<dyn DumpObserver>) -> RetryPolicy {
+    let hook = retry.on_retry.clone();
+    let observer = observer.clone();
+    retry.clone().with_on_retry(move |v| {
+        if let Some(hook) = &hook {
+            hook(v);
+        }
+        observer.on_event(&DumpEvent::RequestRetried {
+            url: v.url.to_string(),
+            retry: v.retry,
+            delay: v.delay,
+            reason: v.reason.to_string(),
+        })
+    })
+}
+
+pub async fn dump_helper(
+    methods: &impl Methods,
+    auth_token: &HydrolixToken,
+    options: &DumpOptions,
+) -> Result<(Box<cluster::Cluster>, DumpReport)> {
+    let started = Instant::now();
+    let walker = Walker {
+        methods,
+        options,
+        bearer: BearerToken::from(auth_token),
+        base_url: auth_token.base_url.to_string(),
+        concurrency: options.concurrency.max(1),
+        permits: Semaphore::new(options.concurrency.max(1)),
+        failures: Mutex::new(vec![]),
+    };
 
-pub async fn dump(auth_token: &HydrolixToken) -> Result<Box<cluster::Cluster>, String> {
     let mut cluster: Box<cluster::Cluster> = Box::new(cluster::Cluster {
         base_url: auth_token.base_url.to_string(),
         orgs: None,
     });
 
     for org in &auth_token.org_list {
-        let mut root_org: org::Org = org::Org {
-            name: org.name.to_string(),
-            uuid: org.uuid.to_string(),
-            cloud: org.cloud.to_string(),
-            kubernetes: org.kubernetes,
-            projects: None,
-            storages: None,
-        };
+        if !options.orgs.matches(&org.name, &org.uuid) {
+            continue;
+        }
+        let root_org = walker.org(org).await?;
+        append(&mut cluster.orgs, vec![root_org]);
+    }
 
-        {
-            // /config/v1/orgs/{org_id}/storages/
-            let url = format!(
-                "https://{}/config/v1/orgs/{}/storages",
-                auth_token.base_url, root_org.uuid
-            );
+    // Sort so the report does not depend on the order requests finished in
+    let mut failures = walker.failures.into_inner().unwrap_or_default();
+    failures.sort();
 
-            let json_data = match http::get_paginated(&auth_token.value, &url).await {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+    if let Some(observer) = &options.observer {
+        observer.on_event(&totals(&cluster, failures.len(), started.elapsed()));
+    }
 
-            let mut storages: Vec<storage::Storage> = match serde_json::from_str(&json_data) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+    Ok((cluster, DumpReport { failures }))
+}
 
-            for s in &mut storages {
-                if root_org.storages.is_none() {
-                    root_org.storages = Some(Vec::new());
-                }
-                if let Some(storage_vec) = &mut root_org.storages {
-                    storage_vec.push(s.clone());
-                }
-            }
-        }
+// Where in the cluster a list is fetched from, for the report
+#[derive(Clone)]
+struct Scope {
+    org: String,
+    project: Option<String>,
+    table: Option<String>,
+}
 
-        let url = format!(
-            "https://{}/config/v1/orgs/{}/projects",
-            auth_token.base_url, root_org.uuid
-        );
+// The kinds of resources listed while walking a cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resource {
+    Storages,
+    Projects,
+    Functions,
+    Tables,
+    Transforms,
+}
 
-        let json_data = match http::get_paginated(&auth_token.value, &url).await {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(format!("{}.{} Error: {e}", file!(), line!()));
-            }
-        };
+impl Resource {
+    fn as_str(self) -> &'static str {
+        match self {
+            Resource::Storages => "storages",
+            Resource::Projects => "projects",
+            Resource::Functions => "functions",
+            Resource::Tables => "tables",
+            Resource::Transforms => "transforms",
+        }
+    }
 
-        let mut projects: Vec<project::Project> = match serde_json::from_str(&json_data) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(format!("{}.{} Error: {e}", file!(), line!()));
-            }
-        };
+    // Whether `options` asks for this kind of resource at all
+    fn enabled(self, options: &DumpOptions) -> bool {
+        match self {
+            Resource::Storages => options.storages,
+            Resource::Functions => options.functions,
+            Resource::Transforms => options.transforms,
+            Resource::Projects | Resource::Tables => true,
+        }
+    }
+}
 
-        for p in &mut projects {
-            let url = format!(
-                "https://{}/config/v1/orgs/{}/projects/{}/functions",
-                auth_token.base_url, org.uuid, p.uuid
-            );
+// Walks the config API.  Every request holds a permit, so the number of
+// requests in flight never exceeds the concurrency limit however the walk
+// fans out.
+struct Walker<'a, M: Methods> {
+    methods: &'a M,
+    options: &'a DumpOptions,
+    bearer: BearerToken,
+    base_url: String,
+    concurrency: usize,
+    permits: Semaphore,
+    failures: Mutex<Vec<DumpFailure>>,
+}
 
-            let json_data = match http::get_paginated(&auth_token.value, &url).await {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+impl<M: Methods> Walker<'_, M> {
+    async fn list<T: DeserializeOwned>(
+        &self,
+        scope: &Scope,
+        resource: Resource,
+        path: String,
+    ) -> Result<Vec<T>> {
+        if !resource.enabled(self.options) {
+            return Ok(vec![]);
+        }
 
-            let functions: Vec<function::Function> = match serde_json::from_str(&json_data) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+        let url = Url::new(format!("https://{}/config/v1/orgs/{path}", self.base_url));
+        let result = {
+            // The semaphore is never closed, so acquiring can't fail
+            let _permit = self.permits.acquire().await.ok();
+            http::get_paginated_helper(&url, &self.bearer, self.methods).await
+        };
 
-            for f in &functions {
-                if p.functions.is_none() {
-                    p.functions = Some(Vec::new());
-                }
+        let e = match result {
+            Ok(v) => return Ok(v),
+            Err(e) if self.options.failure_mode == FailureMode::FailFast => return Err(e),
+            Err(e) => e,
+        };
 
-                if let Some(functions_vec) = &mut p.functions {
-                    functions_vec.push(f.clone());
-                }
-            }
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.push(DumpFailure {
+                org: scope.org.to_string(),
+                project: scope.project.clone(),
+                table: scope.table.clone(),
+                resource: resource.as_str().to_string(),
+                url: url.to_string(),
+                status: e.status(),
+                error: e.to_string(),
+            });
         }
+        Ok(vec![])
+    }
 
-        for p in &mut projects {
-            let url = format!(
-                "https://{}/config/v1/orgs/{}/projects/{}/tables",
-                auth_token.base_url, org.uuid, p.uuid
-            );
+    fn emit(&self, event: DumpEvent) {
+        if let Some(observer) = &self.options.observer {
+            observer.on_event(&event);
+        }
+    }
 
-            let json_data = match http::get_paginated(&auth_token.value, &url).await {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+    async fn org(&self, org: &auth::Org) -> Result<org::Org> {
+        self.emit(DumpEvent::OrgStarted {
+            org: org.name.to_string(),
+        });
+        let mut root_org: org::Org = org::Org {
+            name: org.name.to_string(),
+            uuid: org.uuid.to_string(),
+            cloud: org.cloud.to_string(),
+            kubernetes: org.kubernetes,
+            projects: None,
+            storages: None,
+        };
 
-            let mut tables: Vec<table::Table> = match serde_json::from_str(&json_data) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                }
-            };
+        let scope = Scope {
+            org: org.name.to_string(),
+            project: None,
+            table: None,
+        };
 
-            // Because transforms are only per table, add them to the local table,
-            // and then copy those to the project
-            for t in &mut tables {
-                let url = format!(
-                    "https://{}/config/v1/orgs/{}/projects/{}/tables/{}/transforms/",
-                    auth_token.base_url, org.uuid, p.uuid, t.uuid
-                );
-
-                let json_data = match http::get_paginated(&auth_token.value, &url).await {
-                    Ok(v) => v,
-                    Err(e) => {
-                        return Err(format!("{}.{} Error: {e}", file!(), line!()));
-                    }
-                };
-
-                let mut transforms: Vec<transform::Transform> =
-                    match serde_json::from_str(&json_data) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            return Err(format!("{}.{} Error: {url} {e}", file!(), line!()));
-                        }
-                    };
-
-                for z in &mut transforms {
-                    if t.transforms.is_none() {
-                        t.transforms = Some(Vec::new());
-                    }
-
-                    if let Some(transforms_vec) = &mut t.transforms {
-                        transforms_vec.push(z.clone());
-                    }
-                }
-            }
-            // Now we walk through the tables and append them
-            // to the project
-            for t in &mut tables {
-                if p.tables.is_none() {
-                    p.tables = Some(Vec::new());
-                }
-
-                if let Some(tables_vec) = &mut p.tables {
-                    tables_vec.push(t.clone());
-                }
-            }
-            if root_org.projects.is_none() {
-                root_org.projects = Some(Vec::new());
-            }
-            if let Some(project_vec) = &mut root_org.projects {
-                project_vec.push(p.clone());
-            }
-        }
+        // /config/v1/orgs/{org_id}/storages/
+        let (storages, projects) = try_join(
+            self.list::<storage::Storage>(
+                &scope,
+                Resource::Storages,
+                format!("{}/storages", org.uuid),
+            ),
+            self.list::<project::Project>(
+                &scope,
+                Resource::Projects,
+                format!("{}/projects", org.uuid),
+            ),
+        )
+        .await?;
+        append(&mut root_org.storages, storages);
+
+        // `buffered` keeps the projects in the order the API returned them
+        let projects: Vec<project::Project> = stream::iter(projects)
+            .filter(|p| futures::future::ready(self.options.projects.matches(&p.name, &p.uuid)))
+            .map(|p| self.project(&scope, &org.uuid, p))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+        append(&mut root_org.projects, projects);
+
+        Ok(root_org)
+    }
 
-        if cluster.orgs.is_none() {
-            cluster.orgs = Some(Vec::new());
-        }
-        if let Some(temp_vec) = &mut cluster.orgs {
-            temp_vec.push(root_org.clone());
-        }
+    async fn project(
+        &self,
+        scope: &Scope,
+        org_uuid: &str,
+        mut p: project::Project,
+    ) -> Result<project::Project> {
+        let scope = Scope {
+            project: Some(p.name.to_string()),
+            ..scope.clone()
+        };
+        let (functions, tables) = try_join(
+            self.list::<function::Function>(
+                &scope,
+                Resource::Functions,
+                format!("{org_uuid}/projects/{}/functions", p.uuid),
+            ),
+            self.list::<table::Table>(
+                &scope,
+                Resource::Tables,
+                format!("{org_uuid}/projects/{}/tables", p.uuid),
+            ),
+        )
+        .await?;
+        let tables: Vec<table::Table> = tables
+            .into_iter()
+            .filter(|t| self.options.tables.matches(&t.name, &t.uuid))
+            .collect();
+        self.emit(DumpEvent::ProjectFetched {
+            org: scope.org.to_string(),
+            project: p.name.to_string(),
+            functions: functions.len(),
+            tables: tables.len(),
+        });
+        append(&mut p.functions, functions);
+
+        // Because transforms are only per table, add them to the table,
+        // and then append the tables to the project
+        let tables: Vec<table::Table> = stream::iter(tables)
+            .map(|t| self.table(&scope, org_uuid, &p.uuid, t))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+        append(&mut p.tables, tables);
+
+        Ok(p)
+    }
 
-        //_ = save_pretty_json(&json_value, "pretty_test.json").is_ok();
-        //panic!("We are done!!!");
+    async fn table(
+        &self,
+        scope: &Scope,
+        org_uuid: &str,
+        project_uuid: &str,
+        mut t: table::Table,
+    ) -> Result<table::Table> {
+        let scope = Scope {
+            table: Some(t.name.to_string()),
+            ..scope.clone()
+        };
+        let transforms: Vec<transform::Transform> = self
+            .list(
+                &scope,
+                Resource::Transforms,
+                format!(
+                    "{org_uuid}/projects/{project_uuid}/tables/{}/transforms/",
+                    t.uuid
+                ),
+            )
+            .await?;
+        self.emit(DumpEvent::TransformsFetched {
+            org: scope.org.to_string(),
+            project: scope.project.clone().unwrap_or_default(),
+            table: t.name.to_string(),
+            transforms: transforms.len(),
+        });
+        append(&mut t.transforms, transforms);
+        Ok(t)
     }
+}
 
-    Ok(cluster)
+fn totals(cluster: &cluster::Cluster, failures: usize, elapsed: Duration) -> DumpEvent {
+    let orgs = cluster.orgs.as_deref().unwrap_or_default();
+    let projects: Vec<&project::Project> = orgs
+        .iter()
+        .flat_map(|o| o.projects.iter().flatten())
+        .collect();
+    let tables: Vec<&table::Table> = projects
+        .iter()
+        .flat_map(|p| p.tables.iter().flatten())
+        .collect();
+    DumpEvent::Finished {
+        orgs: orgs.len(),
+        storages: orgs
+            .iter()
+            .map(|o| o.storages.as_deref().unwrap_or_default().len())
+            .sum(),
+        projects: projects.len(),
+        functions: projects
+            .iter()
+            .map(|p| p.functions.as_deref().unwrap_or_default().len())
+            .sum(),
+        tables: tables.len(),
+        transforms: tables
+            .iter()
+            .map(|t| t.transforms.as_deref().unwrap_or_default().len())
+            .sum(),
+        failures,
+        elapsed,
+    }
+}
+
+// Empty lists are left as `None`, so the output only has the resources that exist
+fn append<T>(target: &mut Option<Vec<T>>, items: Vec<T>) {
+    if items.is_empty() {
+        return;
+    }
+    target.get_or_insert_with(Vec::new).extend(items);
 }
 
 #[cfg(test)]
@@ -199,10 +619,16 @@ mod tests {
     use std::fs;
     use std::io;
 
+    use std::sync::{Arc, Mutex};
+
     use crate::auth::HydrolixAuth;
+    use crate::http::Method;
     use crate::hydrolix::secrets;
+    use crate::mock;
+    use crate::retry::{Retry, RetryPolicy};
+    use tokio::time::Duration;
 
-    use super::dump;
+    use super::{dump, dump_helper, observe_retries, DumpEvent, DumpOptions, FailureMode, Filter};
 
     fn print_pretty_json(json_data: &Value) -> Result<(), String> {
         // Write the JSON in a pretty-printed format to standard output
@@ -214,6 +640,259 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn dump_mock_cluster() {
+        let methods = mock::cluster();
+        let (cluster, report) =
+            match dump_helper(&methods, &mock::token(), &DumpOptions::default()).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+        assert!(report.is_complete());
+
+        let orgs = cluster.orgs.unwrap_or_default();
+        assert!(orgs.len() == 1);
+        assert!(orgs[0].storages.as_ref().map(|v| v.len()) == Some(1));
+        let projects = orgs[0].projects.clone().unwrap_or_default();
+        let names: Vec<&str> = projects.iter().map(|v| v.name.as_str()).collect();
+        assert!(names == ["p1", "p2"]);
+        assert!(projects[0].functions.as_ref().map(|v| v.len()) == Some(1));
+        assert!(projects[1].functions.is_none());
+        let tables = projects[0].tables.clone().unwrap_or_default();
+        assert!(tables.len() == 2);
+        assert!(tables[1].transforms.as_ref().map(|v| v[0].name.as_str()) == Some("t2_transform"));
+    }
+
+    #[tokio::test]
+    async fn dump_concurrent_matches_sequential() {
+        let sequential = mock::cluster().with_delay(Duration::from_millis(5));
+        let (expected, _) =
+            match dump_helper(&sequential, &mock::token(), &DumpOptions::default()).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+        assert!(sequential.max_in_flight() == 1);
+
+        for concurrency in [2, 3, 16] {
+            let concurrent = mock::cluster().with_delay(Duration::from_millis(5));
+            let options = DumpOptions::default().with_concurrency(concurrency);
+            let (actual, _) = match dump_helper(&concurrent, &mock::token(), &options).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+            assert!(concurrent.max_in_flight() <= concurrency);
+            assert!(concurrent.max_in_flight() > 1);
+
+            let expected = to_value(&expected).unwrap_or_default();
+            let actual = to_value(&actual).unwrap_or_default();
+            assert!(!expected.is_null() && expected == actual);
+        }
+    }
+
+    #[tokio::test]
+    async fn dump_best_effort() {
+        let broken = "https://h/config/v1/orgs/o1/projects/p1/tables/t2/transforms/?page=1";
+        let mismatched = "https://h/config/v1/orgs/o1/projects/p2/functions?page=1";
+        let methods = || {
+            mock::cluster()
+                .with_override(Method::GET, broken, 500, "boom")
+                .with_override(Method::GET, mismatched, 200, r#"[{"name": 1}]"#)
+        };
+
+        // Fail fast gives up on the first error
+        let methods_fail_fast = methods();
+        match dump_helper(&methods_fail_fast, &mock::token(), &DumpOptions::default()).await {
+            Ok(_) => panic!("The dump should have failed"),
+            Err(e) => assert!(e.url().is_some()),
+        }
+
+        let options = DumpOptions::default()
+            .with_concurrency(4)
+            .with_failure_mode(FailureMode::BestEffort);
+        let (cluster, report) = match dump_helper(&methods(), &mock::token(), &options).await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to dump: {e}"),
+        };
+
+        // Everything else is still there
+        let projects = cluster.orgs.unwrap_or_default()[0]
+            .projects
+            .clone()
+            .unwrap_or_default();
+        let tables = projects[0].tables.clone().unwrap_or_default();
+        assert!(tables[0].transforms.is_some());
+        assert!(tables[1].transforms.is_none());
+        assert!(projects[1].tables.as_ref().map(|v| v.len()) == Some(1));
+
+        assert!(!report.is_complete());
+        assert!(report.failures.len() == 2);
+        let functions = &report.failures[1];
+        assert!(functions.project.as_deref() == Some("p2"));
+        assert!(functions.table.is_none());
+        assert!(functions.resource == "functions");
+        assert!(mismatched.starts_with(&functions.url));
+        assert!(functions.error.contains("[0].name"));
+        let transforms = &report.failures[0];
+        assert!(transforms.org == "org1");
+        assert!(transforms.project.as_deref() == Some("p1"));
+        assert!(transforms.table.as_deref() == Some("t2"));
+        assert!(transforms.resource == "transforms");
+        assert!(transforms.status == Some(500));
+    }
+
+    #[test]
+    fn filter_matches() {
+        let all = Filter::default();
+        assert!(all.matches("anything", "uuid"));
+
+        let filter = Filter::default()
+            .include("staging_*")
+            .include("1234-abcd")
+            .exclude("staging_old");
+        assert!(filter.matches("staging_web", "x"));
+        assert!(filter.matches("prod", "1234-abcd"));
+        assert!(!filter.matches("staging_old", "x"));
+        assert!(!filter.matches("prod", "x"));
+
+        // A broken glob still matches exactly
+        let filter = Filter::default().include("a[b");
+        assert!(filter.matches("a[b", "x"));
+        assert!(!filter.matches("ab", "x"));
+    }
+
+    #[tokio::test]
+    async fn dump_scoped() {
+        let methods = mock::cluster();
+        let options = DumpOptions::default()
+            .with_projects(Filter::default().include("p1"))
+            .with_tables(Filter::default().exclude("t1"))
+            .without_storages()
+            .without_functions();
+        let (cluster, _) = match dump_helper(&methods, &mock::token(), &options).await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to dump: {e}"),
+        };
+
+        let org = &cluster.orgs.unwrap_or_default()[0];
+        assert!(org.storages.is_none());
+        let projects = org.projects.clone().unwrap_or_default();
+        assert!(projects.len() == 1 && projects[0].name == "p1");
+        assert!(projects[0].functions.is_none());
+        let tables = projects[0].tables.clone().unwrap_or_default();
+        assert!(tables.len() == 1 && tables[0].name == "t2");
+        assert!(tables[0].transforms.is_some());
+
+        // Skipped resources are never requested
+        let urls = methods.requested_urls();
+        assert!(urls.len() == 3, "{urls:?}");
+        assert!(!urls.iter().any(|v| v.contains("storages")
+            || v.contains("functions")
+            || v.contains("/p2/")
+            || v.contains("/t1/")));
+
+        // Filtering out the only org makes no request at all
+        let methods = mock::cluster();
+        let options = DumpOptions::default()
+            .with_orgs(Filter::default().exclude("org1"))
+            .without_transforms();
+        match dump_helper(&methods, &mock::token(), &options).await {
+            Ok((v, _)) => assert!(v.orgs.is_none()),
+            Err(e) => panic!("Failed to dump: {e}"),
+        }
+        assert!(methods.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dump_events() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let options = DumpOptions::default()
+            .with_concurrency(4)
+            .with_observer(sender);
+        if let Err(e) = dump_helper(&mock::cluster(), &mock::token(), &options).await {
+            panic!("Failed to dump: {e}");
+        }
+        drop(options);
+
+        let mut events = vec![];
+        while let Some(v) = receiver.recv().await {
+            events.push(v);
+        }
+        assert!(
+            events.first()
+                == Some(&DumpEvent::OrgStarted {
+                    org: "org1".to_string()
+                })
+        );
+        assert!(events.contains(&DumpEvent::ProjectFetched {
+            org: "org1".to_string(),
+            project: "p1".to_string(),
+            functions: 1,
+            tables: 2,
+        }));
+        assert!(events.contains(&DumpEvent::TransformsFetched {
+            org: "org1".to_string(),
+            project: "p2".to_string(),
+            table: "t3".to_string(),
+            transforms: 1,
+        }));
+        match events.last() {
+            Some(DumpEvent::Finished {
+                orgs,
+                storages,
+                projects,
+                functions,
+                tables,
+                transforms,
+                failures,
+                ..
+            }) => {
+                assert!((*orgs, *storages, *projects, *functions) == (1, 1, 2, 1));
+                assert!((*tables, *transforms, *failures) == (3, 3, 0));
+            }
+            v => panic!("Expected the totals last, got {v:?}"),
+        }
+        assert!(events.len() == 7);
+    }
+
+    #[test]
+    fn test_retries_keep_the_hook() {
+        let retries = Arc::new(Mutex::new(vec![]));
+        let seen = retries.clone();
+        let policy = RetryPolicy::default().with_on_retry(move |v| {
+            seen.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(v.clone())
+        });
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let options = DumpOptions::default().with_observer(sender);
+        let Some(observer) = &options.observer else {
+            panic!("No observer");
+        };
+
+        let retry = Retry {
+            url: "https://h/config/v1/orgs/".to_string(),
+            retry: 1,
+            delay: Duration::from_millis(500),
+            reason: "503".to_string(),
+        };
+        match &observe_retries(&policy, observer).on_retry {
+            Some(hook) => hook(&retry),
+            None => panic!("No retry hook"),
+        }
+
+        let retries = retries.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(*retries == vec![retry]);
+        assert!(
+            receiver.try_recv().ok()
+                == Some(DumpEvent::RequestRetried {
+                    url: "https://h/config/v1/orgs/".to_string(),
+                    retry: 1,
+                    delay: Duration::from_millis(500),
+                    reason: "503".to_string(),
+                })
+        );
+    }
+
     #[tokio::test]
     async fn read_config() {
         let file_path = "/tmp/fleet.secrets.toml";
@@ -259,7 +938,7 @@ mod tests {
                 Ok(v) => v,
                 Err(e) => panic!("Failed to authenticate: {e}"),
             };
-            assert!(token.org_list.len() > 0);
+            assert!(!token.org_list.is_empty());
         }
     }
 
@@ -0,0 +1,184 @@
+use std::fmt;
+
+use crate::auth::AuthError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Every fallible public function in the crate returns this error
+#[derive(Debug)]
+pub enum Error {
+    // The request never produced a response (connection, TLS, timeout, ...)
+    Transport {
+        url: String,
+        source: reqwest::Error,
+    },
+    // The cluster answered with a non-success status code
+    Status {
+        url: String,
+        status: u16,
+        body: String,
+    },
+    // Logging in to the cluster failed
+    Auth(AuthError),
+    // The response did not match the expected type. `path` is the serde path
+    // to the offending field, e.g. `[3].settings.stream.token_list`
+    Deserialize {
+        url: String,
+        path: String,
+        source: serde_json::Error,
+    },
+    // A CSV or TSV response did not match the expected type
+    Csv {
+        url: String,
+        source: csv::Error,
+    },
+    // The response can't be used although it parsed, e.g. a `next` page link
+    // that is invalid or loops back
+    Response {
+        url: String,
+        message: String,
+    },
+    // Data to send could not be serialized, e.g. events written as CSV
+    Serialize(String),
+    // A query ran past its client side deadline and was cancelled
+    Timeout {
+        url: String,
+        after: std::time::Duration,
+    },
+    // Reading or parsing local configuration failed
+    Config(String),
+    // Reading or writing a local file failed
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    // An external command such as `git` failed
+    Command {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+}
+
+impl Error {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Status { status, .. } => Some(*status),
+            Error::Auth(e) => e.status,
+            Error::Transport { source, .. } => source.status().map(|v| v.as_u16()),
+            _ => None,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Transport { source, .. } => source.is_timeout(),
+            Error::Timeout { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            Error::Transport { url, .. }
+            | Error::Status { url, .. }
+            | Error::Deserialize { url, .. }
+            | Error::Csv { url, .. }
+            | Error::Response { url, .. }
+            | Error::Timeout { url, .. } => Some(url),
+            Error::Auth(e) => Some(&e.url),
+            Error::Config(_) | Error::Serialize(_) | Error::Io { .. } | Error::Command { .. } => {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport { url, source } => write!(f, "Request failed: url={url} {source}"),
+            Error::Status { url, status, body } => {
+                write!(f, "Unexpected status {status}: url={url}")?;
+                if !body.is_empty() {
+                    write!(f, " body={body}")?;
+                }
+                Ok(())
+            }
+            Error::Auth(e) => write!(f, "{e}"),
+            Error::Deserialize { url, path, source } => {
+                write!(f, "Failed to parse data: url={url} path={path} {source}")
+            }
+            Error::Csv { url, source } => write!(f, "Failed to parse CSV: url={url} {source}"),
+            Error::Response { url, message } => {
+                write!(f, "Invalid response: url={url} {message}")
+            }
+            Error::Serialize(message) => write!(f, "Failed to serialize {message}"),
+            Error::Timeout { url, after } => write!(f, "Timed out after {after:?}: url={url}"),
+            Error::Config(message) => write!(f, "Invalid configuration: {message}"),
+            Error::Io { path, source } => write!(f, "File error: path={path} {source}"),
+            Error::Command {
+                command,
+                status,
+                stderr,
+            } => write!(f, "Command failed: {command} status={status:?} {stderr}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport { source, .. } => Some(source),
+            Error::Auth(e) => Some(e),
+            Error::Deserialize { source, .. } => Some(source),
+            Error::Csv { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Self {
+        Error::Auth(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use crate::http;
+    use crate::hydrolix::function::Function;
+
+    #[test]
+    fn test_deserialize_path() {
+        let data = r#"[{"name": "f", "project": "p", "description": "", "uuid": 7}]"#;
+        let e = match http::from_json::<Vec<Function>>("https://x/functions", data) {
+            Ok(_) => panic!("Parsing a number as uuid should fail"),
+            Err(e) => e,
+        };
+        match &e {
+            Error::Deserialize { url, path, .. } => {
+                assert!(url == "https://x/functions");
+                assert!(path == "[0].uuid");
+            }
+            _ => panic!("Unexpected error {e}"),
+        }
+        assert!(e.url() == Some("https://x/functions"));
+        assert!(e.status().is_none());
+    }
+
+    #[test]
+    fn test_status_display() {
+        let e = Error::Status {
+            url: "https://x/config/v1/orgs".to_string(),
+            status: 404,
+            body: "not found".to_string(),
+        };
+        assert!(e.status() == Some(404));
+        assert!(
+            e.to_string() == "Unexpected status 404: url=https://x/config/v1/orgs body=not found"
+        );
+    }
+}
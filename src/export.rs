@@ -0,0 +1,390 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::http;
+use crate::hydrolix::cluster;
+use crate::hydrolix::function;
+use crate::hydrolix::org;
+use crate::hydrolix::project;
+use crate::hydrolix::storage;
+use crate::hydrolix::table;
+use crate::hydrolix::transform;
+
+// Writes a cluster as one pretty printed JSON file per resource, so a change
+// to a single transform shows up as a small diff in code review:
+//
+//   <root>/cluster.json
+//   <root>/org/<org>/org.json
+//   <root>/org/<org>/storage/<storage>.json
+//   <root>/org/<org>/project/<project>/project.json
+//   <root>/org/<org>/project/<project>/function/<function>.json
+//   <root>/org/<org>/project/<project>/table/<table>/table.json
+//   <root>/org/<org>/project/<project>/table/<table>/transform/<transform>.json
+//
+// Keys are sorted, so the same configuration always produces the same files.
+// An existing `<root>/org` tree is replaced, so deleted resources disappear.
+// File names don't depend on the order of the API: they are picked in name
+// and UUID order, and `load_tree` returns resources in file name order.
+pub fn export_tree(cluster: &cluster::Cluster, root: &Path) -> Result<()> {
+    let org_root = root.join("org");
+    if org_root.exists() {
+        if let Err(e) = fs::remove_dir_all(&org_root) {
+            return Err(io_error(&org_root, e));
+        }
+    }
+
+    write_json(&root.join("cluster.json"), cluster, &["orgs"])?;
+
+    for (org_name, o) in named(&cluster.orgs, "", |v| (&v.name, &v.uuid)) {
+        let org_dir = org_root.join(org_name);
+        write_json(&org_dir.join("org.json"), o, &["projects", "storages"])?;
+
+        for (file, s) in named(&o.storages, ".json", |v| (&v.name, &v.uuid)) {
+            write_json(&org_dir.join("storage").join(file), s, &[])?;
+        }
+
+        for (project_name, p) in named(&o.projects, "", |v| (&v.name, &v.uuid)) {
+            let project_dir = org_dir.join("project").join(project_name);
+            write_json(
+                &project_dir.join("project.json"),
+                p,
+                &["tables", "functions"],
+            )?;
+
+            for (file, f) in named(&p.functions, ".json", |v| (&v.name, &v.uuid)) {
+                write_json(&project_dir.join("function").join(file), f, &[])?;
+            }
+
+            for (table_name, t) in named(&p.tables, "", |v| (&v.name, &v.uuid)) {
+                let table_dir = project_dir.join("table").join(table_name);
+                write_json(&table_dir.join("table.json"), t, &["transforms"])?;
+
+                for (file, z) in named(&t.transforms, ".json", |v| (&v.name, &v.uuid)) {
+                    write_json(&table_dir.join("transform").join(file), z, &[])?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Rebuild a cluster from a tree written by `export_tree`.  Resources are
+// ordered by their file name.
+pub fn load_tree(root: &Path) -> Result<cluster::Cluster> {
+    let mut cluster: cluster::Cluster = read_json(&root.join("cluster.json"))?;
+
+    for org_dir in sub_dirs(&root.join("org"))? {
+        let mut o: org::Org = read_json(&org_dir.join("org.json"))?;
+        o.storages = read_all::<storage::Storage>(&org_dir.join("storage"))?;
+
+        let mut projects = vec![];
+        for project_dir in sub_dirs(&org_dir.join("project"))? {
+            let mut p: project::Project = read_json(&project_dir.join("project.json"))?;
+            p.functions = read_all::<function::Function>(&project_dir.join("function"))?;
+
+            let mut tables = vec![];
+            for table_dir in sub_dirs(&project_dir.join("table"))? {
+                let mut t: table::Table = read_json(&table_dir.join("table.json"))?;
+                t.transforms = read_all::<transform::Transform>(&table_dir.join("transform"))?;
+                tables.push(t);
+            }
+            p.tables = some_if_any(tables);
+            projects.push(p);
+        }
+        o.projects = some_if_any(projects);
+
+        cluster.orgs.get_or_insert_with(Vec::new).push(o);
+    }
+
+    Ok(cluster)
+}
+
+// Pretty JSON with sorted keys and a trailing newline.  `children` are left
+// out, they get their own files.
+pub fn to_pretty_json<T: Serialize>(value: &T, children: &[&str]) -> Result<String> {
+    let mut value = match serde_json::to_value(value) {
+        Ok(v) => v,
+        Err(e) => return Err(Error::Serialize(format!("resource as JSON: {e}"))),
+    };
+    if let Value::Object(map) = &mut value {
+        for child in children {
+            map.remove(*child);
+        }
+    }
+    match serde_json::to_string_pretty(&value) {
+        Ok(v) => Ok(v + "\n"),
+        Err(e) => Err(Error::Serialize(format!("resource as JSON: {e}"))),
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T, children: &[&str]) -> Result<()> {
+    let data = to_pretty_json(value, children)?;
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            return Err(io_error(parent, e));
+        }
+    }
+    match fs::write(path, data) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(io_error(path, e)),
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    match fs::read_to_string(path) {
+        Ok(v) => http::from_json(&path.display().to_string(), &v),
+        Err(e) => Err(io_error(path, e)),
+    }
+}
+
+// Every `*.json` file in `dir`, sorted by file name.  A missing directory means none.
+fn read_all<T: DeserializeOwned>(dir: &Path) -> Result<Option<Vec<T>>> {
+    let mut items = vec![];
+    for path in entries(dir)? {
+        if path.is_file() && path.extension().is_some_and(|v| v == "json") {
+            items.push(read_json(&path)?);
+        }
+    }
+    Ok(some_if_any(items))
+}
+
+fn sub_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(entries(dir)?.into_iter().filter(|v| v.is_dir()).collect())
+}
+
+fn entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let read_dir = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) => return Err(io_error(dir, e)),
+    };
+    let mut paths = vec![];
+    for entry in read_dir {
+        match entry {
+            Ok(v) => paths.push(v.path()),
+            Err(e) => return Err(io_error(dir, e)),
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn some_if_any<T>(items: Vec<T>) -> Option<Vec<T>> {
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+fn io_error(path: &Path, source: std::io::Error) -> Error {
+    Error::Io {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+// File names for `items`, ordered by file name the way `load_tree` reads them
+// back.  `suffix` is the extension of leaf files, it takes part in the order.
+fn named<'a, T>(
+    items: &'a Option<Vec<T>>,
+    suffix: &str,
+    key: impl Fn(&T) -> (&str, &str),
+) -> Vec<(String, &'a T)> {
+    let mut items: Vec<&T> = items.iter().flatten().collect();
+    items.sort_by(|a, b| key(a).cmp(&key(b)));
+
+    let mut names = Names::default();
+    let mut named: Vec<(String, &T)> = items
+        .into_iter()
+        .map(|v| {
+            let (name, uuid) = key(v);
+            (names.pick(name, uuid) + suffix, v)
+        })
+        .collect();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    named
+}
+
+// File names used so far in one directory.  Names are made safe for the file
+// system, and the UUID is appended when two resources would collide.
+#[derive(Default)]
+struct Names {
+    used: HashSet<String>,
+}
+
+impl Names {
+    fn pick(&mut self, name: &str, uuid: &str) -> String {
+        let mut file_name = sanitize(name);
+        if file_name.is_empty() {
+            file_name = sanitize(uuid);
+        }
+        if self.used.contains(&file_name) {
+            file_name = format!("{file_name}.{}", sanitize(uuid));
+        }
+        self.used.insert(file_name.to_string());
+        file_name
+    }
+}
+
+pub fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // Never produce `.` or `..`
+    cleaned.trim_start_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_tree, load_tree, sanitize};
+    use crate::dump::{dump_helper, DumpOptions};
+    use crate::hydrolix::cluster::Cluster;
+    use crate::hydrolix::function::Function;
+    use crate::mock::{self, fixtures};
+    use serde_json::{json, to_value};
+    use std::fs;
+
+    #[test]
+    fn test_sanitize() {
+        assert!(sanitize("web_logs-2") == "web_logs-2");
+        assert!(sanitize("a/b c") == "a_b_c");
+        assert!(sanitize("..").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_and_load() {
+        let (cluster, _) =
+            match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+
+        let root = std::env::temp_dir().join(format!("hdx_export_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        if let Err(e) = export_tree(&cluster, &root) {
+            panic!("Failed to export: {e}");
+        }
+
+        let transform = root.join("org/org1/project/p1/table/t2/transform/t2_transform.json");
+        let content = match fs::read_to_string(&transform) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to read {}: {e}", transform.display()),
+        };
+        assert!(content.ends_with("}\n"));
+        assert!(content.find("\"created\"") < content.find("\"name\""));
+        assert!(root.join("org/org1/storage/s1.json").is_file());
+        assert!(root.join("org/org1/project/p1/function/f1.json").is_file());
+        let project = fs::read_to_string(root.join("org/org1/project/p1/project.json"));
+        assert!(project.is_ok_and(|v| !v.contains("\"tables\"")));
+
+        let loaded = match load_tree(&root) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to load: {e}"),
+        };
+        assert!(to_value(&loaded).ok() == to_value(&*cluster).ok());
+
+        // Exporting again drops resources that are gone
+        let mut smaller = loaded.clone();
+        if let Some(orgs) = &mut smaller.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects.truncate(1);
+            }
+        }
+        if let Err(e) = export_tree(&smaller, &root) {
+            panic!("Failed to export: {e}");
+        }
+        assert!(!root.join("org/org1/project/p2").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_export_order() {
+        let (cluster, _) =
+            match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+        let functions: Vec<Function> = match serde_json::from_value(json!([
+            fixtures::function("p1", "x-1", "f3"),
+            fixtures::function("p1", "x", "f2"),
+            fixtures::function("p1", "x", "f1"),
+        ])) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse functions: {e}"),
+        };
+
+        // Names out of order, as an API may return them
+        let mut unsorted = (*cluster).clone();
+        if let Some(orgs) = &mut unsorted.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects.reverse();
+                projects[1].functions = Some(functions.clone());
+            }
+        }
+        let mut reversed = unsorted.clone();
+        if let Some(orgs) = &mut reversed.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects.reverse();
+                if let Some(functions) = &mut projects[0].functions {
+                    functions.reverse();
+                }
+            }
+        }
+
+        let root = std::env::temp_dir().join(format!("hdx_export_order_{}", std::process::id()));
+        let load = |cluster: &Cluster| {
+            let _ = fs::remove_dir_all(&root);
+            if let Err(e) = export_tree(cluster, &root) {
+                panic!("Failed to export: {e}");
+            }
+            match load_tree(&root) {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to load: {e}"),
+            }
+        };
+
+        let loaded = load(&unsorted);
+        assert!(root.join("org/org1/project/p1/function/x.json").is_file());
+        assert!(root
+            .join("org/org1/project/p1/function/x.f2.json")
+            .is_file());
+        let projects = loaded.orgs.as_deref().unwrap_or_default()[0]
+            .projects
+            .as_deref()
+            .unwrap_or_default();
+        let names: Vec<&str> = projects.iter().map(|v| v.name.as_str()).collect();
+        assert!(names == vec!["p1", "p2"]);
+        // Sorted by file name: `x-1.json`, `x.f2.json`, `x.json`
+        let uuids: Vec<&str> = projects[0]
+            .functions
+            .iter()
+            .flatten()
+            .map(|v| v.uuid.as_str())
+            .collect();
+        assert!(uuids == vec!["f3", "f2", "f1"]);
+
+        // The order of the input doesn't matter, and a loaded tree exports
+        // to the same files
+        assert!(to_value(load(&reversed)).ok() == to_value(&loaded).ok());
+        assert!(to_value(load(&loaded)).ok() == to_value(&loaded).ok());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,267 @@
+use futures::{stream, Future, StreamExt};
+use serde::Serialize;
+use tokio::time::{timeout, Duration};
+
+use crate::auth::HydrolixAuth;
+use crate::client::HydrolixClient;
+use crate::dump::{self, DumpOptions, DumpReport};
+use crate::error::Result;
+use crate::hydrolix::cluster;
+use crate::hydrolix::secrets;
+
+pub const DEFAULT_MACHINE_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct FleetOptions {
+    // Machines dumped at once
+    pub concurrency: usize,
+    // Login and dump of one machine, after which it is given up on
+    pub timeout: Duration,
+    pub dump: DumpOptions,
+}
+
+impl Default for FleetOptions {
+    fn default() -> Self {
+        FleetOptions {
+            concurrency: 4,
+            timeout: DEFAULT_MACHINE_TIMEOUT,
+            dump: DumpOptions::default(),
+        }
+    }
+}
+
+impl FleetOptions {
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_dump_options(mut self, dump: DumpOptions) -> Self {
+        self.dump = dump;
+        self
+    }
+}
+
+// A machine that could not be logged in to or dumped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FleetFailure {
+    pub base_url: String,
+    pub status: Option<u16>,
+    pub timed_out: bool,
+    pub error: String,
+}
+
+// Every machine ends up in either `clusters` or `failures`, in the order of
+// the config.  `reports` goes with `clusters`, it lists what a best-effort
+// dump left out.
+#[derive(Debug, Default)]
+pub struct Fleet {
+    pub clusters: Vec<cluster::Cluster>,
+    pub reports: Vec<DumpReport>,
+    pub failures: Vec<FleetFailure>,
+}
+
+impl Fleet {
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty() && self.reports.iter().all(|v| v.is_complete())
+    }
+}
+
+// Log in to and dump every machine of a secrets file.  One machine failing
+// or hanging never stops the others.
+pub async fn dump_fleet(
+    client: &HydrolixClient,
+    config: &secrets::Config,
+    options: &FleetOptions,
+) -> Fleet {
+    let base_urls: Vec<&str> = config
+        .machines
+        .iter()
+        .map(|v| v.base_url.as_str())
+        .collect();
+    fleet_helper(&base_urls, options, |i| async move {
+        let m = &config.machines[i];
+        let auth = HydrolixAuth::new(&m.base_url, &m.username, &m.password)
+            .await
+            .with_client(client.clone());
+        let token = auth.get_token().await?;
+        let (cluster, report) = dump::dump_with_options(client, &token, &options.dump).await?;
+        Ok((*cluster, report))
+    })
+    .await
+}
+
+// Same as `dump_fleet`, reading the secrets file first
+pub async fn dump_fleet_from_path(
+    client: &HydrolixClient,
+    path: &str,
+    options: &FleetOptions,
+) -> Result<Fleet> {
+    let config = secrets::Config::load(path)?;
+    Ok(dump_fleet(client, &config, options).await)
+}
+
+// Run `dump_one` for every machine, by index, with the concurrency limit and
+// timeout of `options`
+pub async fn fleet_helper<F, Fut>(base_urls: &[&str], options: &FleetOptions, dump_one: F) -> Fleet
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<(cluster::Cluster, DumpReport)>>,
+{
+    // Unordered so a hanging machine only holds its own slot, the index puts
+    // the results back in the order of `base_urls`
+    let mut results: Vec<_> = stream::iter(0..base_urls.len())
+        .map(|i| {
+            let dump = timeout(options.timeout, dump_one(i));
+            async move { (i, dump.await) }
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut fleet = Fleet::default();
+    for (base_url, (_, result)) in base_urls.iter().zip(results) {
+        let failure = match result {
+            Ok(Ok((cluster, report))) => {
+                fleet.clusters.push(cluster);
+                fleet.reports.push(report);
+                continue;
+            }
+            Ok(Err(e)) => FleetFailure {
+                base_url: base_url.to_string(),
+                status: e.status(),
+                timed_out: e.is_timeout(),
+                error: e.to_string(),
+            },
+            Err(_) => FleetFailure {
+                base_url: base_url.to_string(),
+                status: None,
+                timed_out: true,
+                error: format!("Gave up after {:?}", options.timeout),
+            },
+        };
+        fleet.failures.push(failure);
+    }
+    fleet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump_fleet, fleet_helper, FleetOptions};
+    use crate::client::HydrolixClient;
+    use crate::dump::DumpReport;
+    use crate::error::Error;
+    use crate::hydrolix::cluster::Cluster;
+    use crate::hydrolix::secrets;
+    use crate::retry::RetryPolicy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_failures_are_isolated() {
+        let base_urls = ["good", "broken", "hanging", "also-good"];
+        let options = FleetOptions::default()
+            .with_concurrency(2)
+            .with_timeout(Duration::from_millis(100));
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let fleet = fleet_helper(&base_urls, &options, |i| {
+            let running = &running;
+            let max_running = &max_running;
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                let result = match base_urls[i] {
+                    "broken" => Err(Error::Config("bad password".to_string())),
+                    "hanging" => {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Err(Error::Config("unreachable".to_string()))
+                    }
+                    v => Ok((
+                        Cluster {
+                            base_url: v.to_string(),
+                            orgs: None,
+                        },
+                        DumpReport::default(),
+                    )),
+                };
+                running.fetch_sub(1, Ordering::SeqCst);
+                result
+            }
+        })
+        .await;
+
+        let clusters: Vec<&str> = fleet.clusters.iter().map(|v| v.base_url.as_str()).collect();
+        assert!(clusters == vec!["good", "also-good"]);
+        assert!(fleet.failures.len() == 2);
+        assert!(fleet.failures[0].base_url == "broken" && !fleet.failures[0].timed_out);
+        assert!(fleet.failures[1].base_url == "hanging" && fleet.failures[1].timed_out);
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        assert!(!fleet.is_complete());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hanging_machine_keeps_its_slot() {
+        let base_urls = ["hanging", "a", "b", "c"];
+        let options = FleetOptions::default()
+            .with_concurrency(2)
+            .with_timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        let started = std::sync::Mutex::new(vec![]);
+
+        let fleet = fleet_helper(&base_urls, &options, |i| {
+            let started = &started;
+            async move {
+                if let Ok(mut v) = started.lock() {
+                    v.push((base_urls[i], start.elapsed()));
+                }
+                let delay = match base_urls[i] {
+                    "hanging" => Duration::from_secs(60),
+                    _ => Duration::from_millis(30),
+                };
+                tokio::time::sleep(delay).await;
+                Ok((
+                    Cluster {
+                        base_url: base_urls[i].to_string(),
+                        orgs: None,
+                    },
+                    DumpReport::default(),
+                ))
+            }
+        })
+        .await;
+
+        // The other machines ran next to the hanging one, not after it
+        let started = match started.lock() {
+            Ok(v) => v.clone(),
+            Err(e) => panic!("Poisoned lock: {e}"),
+        };
+        assert!(started.iter().all(|(_, v)| *v < Duration::from_millis(100)));
+
+        let clusters: Vec<&str> = fleet.clusters.iter().map(|v| v.base_url.as_str()).collect();
+        assert!(clusters == vec!["a", "b", "c"]);
+        assert!(fleet.failures.len() == 1 && fleet.failures[0].base_url == "hanging");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_machine() {
+        let config = match secrets::Config::parse(
+            "[[machines]]\nbase_url = \"127.0.0.1:9\"\nusername = \"u\"\npassword = \"p\"\n\n[git_snapshots]\ntoken = \"\"\n",
+        ) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse config: {e}"),
+        };
+        let client = HydrolixClient::new().with_retry_policy(RetryPolicy::none());
+        let fleet = dump_fleet(&client, &config, &FleetOptions::default()).await;
+        assert!(fleet.clusters.is_empty());
+        assert!(fleet.failures.len() == 1);
+        assert!(fleet.failures[0].base_url == "127.0.0.1:9");
+    }
+}
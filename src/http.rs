@@ -1,111 +1,761 @@
 use async_trait::async_trait;
-use reqwest::header::CONTENT_TYPE;
-use reqwest::Client;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
 use std::str;
-use tokio::time::Duration;
-
-pub async fn get_data(auth_token: &str, url: &str) -> Result<String, String> {
-    let http_client = Client::new();
-
-    // Send the authentication request
-    let response = http_client
-        .get(url)
-        .bearer_auth(auth_token)
-        .header("accept", "application/json")
-        .header(CONTENT_TYPE, "application/json")
-        .timeout(Duration::from_secs(60))
-        .send()
-        .await;
-
-    let response2 = match response {
-        Ok(v) => v, // Capture the response on success
-        Err(e) => return Err(format!("{}.{} Error: {e}", file!(), line!(),)),
-    };
 
-    // Check if the authentication was successful
-    if !response2.status().is_success() {
-        return Err(format!(
-            "{}.{} Error: {:?}",
-            file!(),
-            line!(),
-            response2.status()
-        ));
+use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+
+// The URL and the token are distinct types so they can't be passed in the wrong order
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Url(String);
+
+impl Url {
+    pub fn new(url: impl Into<String>) -> Url {
+        Url(url.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct BearerToken(String);
+
+impl BearerToken {
+    pub fn new(token: impl Into<String>) -> BearerToken {
+        BearerToken(token.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// Never print the token, it ends up in logs
+impl fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BearerToken(<redacted>)")
+    }
+}
+
+impl From<&HydrolixToken> for BearerToken {
+    fn from(token: &HydrolixToken) -> Self {
+        BearerToken(token.value.to_string())
+    }
+}
+
+// Deserialize a response body, keeping the URL and the path to the field that failed
+pub fn from_json<T: DeserializeOwned>(url: &str, data: &str) -> Result<T> {
+    let deserializer = &mut serde_json::Deserializer::from_str(data);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| Error::Deserialize {
+        url: url.to_string(),
+        path: e.path().to_string(),
+        source: e.into_inner(),
+    })
+}
+
+pub async fn get_data(url: &Url, auth_token: &BearerToken) -> Result<String> {
+    HydrolixClient::shared().get_data(url, auth_token).await
+}
+
+pub async fn get_data_with_retry(
+    url: &Url,
+    auth_token: &BearerToken,
+    retry: &RetryPolicy,
+) -> Result<String> {
+    HydrolixClient::shared()
+        .with_retry_policy(retry.clone())
+        .get_data(url, auth_token)
+        .await
+}
+
+// `next` is either the URL of the next page, a page number, or null/0 on the last page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Next {
+    Url(String),
+    Page(u64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaginatedResults<T> {
+    #[serde(default)]
+    pub next: Option<Next>,
+    #[serde(default)]
+    pub count: Option<u64>,
+    pub results: Vec<T>,
+}
+
+pub use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+pub use reqwest::Method;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub auth_token: BearerToken,
+    // Sent as a JSON body when present
+    pub body: Option<Value>,
+    // Sent as a plain text body, e.g. SQL for the query API
+    pub text: Option<String>,
+    // Extra headers, they replace the defaults of the client
+    pub headers: HeaderMap,
+}
+
+impl Request {
+    pub fn new(method: Method, url: &Url, auth_token: &BearerToken) -> Request {
+        Request {
+            method,
+            url: url.clone(),
+            auth_token: auth_token.clone(),
+            body: None,
+            text: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn get(url: &Url, auth_token: &BearerToken) -> Request {
+        Request::new(Method::GET, url, auth_token)
+    }
+
+    pub fn post(url: &Url, auth_token: &BearerToken, body: &Value) -> Request {
+        Request::new(Method::POST, url, auth_token).with_body(body)
     }
 
-    match response2.text().await {
-        Ok(v) => Ok(v),
-        Err(e) => Err(format!(
-            "{}.{} Failed to get json data {e}",
-            file!(),
-            line!()
-        )),
+    pub fn put(url: &Url, auth_token: &BearerToken, body: &Value) -> Request {
+        Request::new(Method::PUT, url, auth_token).with_body(body)
     }
+
+    pub fn patch(url: &Url, auth_token: &BearerToken, body: &Value) -> Request {
+        Request::new(Method::PATCH, url, auth_token).with_body(body)
+    }
+
+    pub fn delete(url: &Url, auth_token: &BearerToken) -> Request {
+        Request::new(Method::DELETE, url, auth_token)
+    }
+
+    pub fn with_body(mut self, body: &Value) -> Request {
+        self.body = Some(body.clone());
+        self
+    }
+
+    pub fn with_text(mut self, text: &str) -> Request {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Request {
+        self.headers.insert(name, value);
+        self
+    }
+
+    // POST and PATCH may not be safe to send twice
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self.method, Method::POST | Method::PATCH)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub url: Url,
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    // Turn a non-success status into `Error::Status`, keeping the body
+    pub fn error_for_status(self) -> Result<Response> {
+        if self.is_success() {
+            return Ok(self);
+        }
+        Err(Error::Status {
+            url: self.url.to_string(),
+            status: self.status,
+            body: self.body,
+        })
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        from_json(self.url.as_str(), &self.body)
+    }
+}
+
+// A response whose body is read as it comes in, e.g. the rows of a query
+pub struct StreamingResponse {
+    pub url: Url,
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Vec<u8>>>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PaginatedResults {
-    pub next: u32,
-    pub count: u32,
-    pub results: Vec<HashMap<String, Value>>,
+impl fmt::Debug for StreamingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamingResponse")
+            .field("url", &self.url)
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
 }
 
+// A body that was already read, as a single chunk
+impl From<Response> for StreamingResponse {
+    fn from(response: Response) -> Self {
+        let body = response.body.into_bytes();
+        StreamingResponse {
+            url: response.url,
+            status: response.status,
+            headers: response.headers,
+            body: stream::once(async move { Ok(body) }).boxed(),
+        }
+    }
+}
+
+// The transport used to talk to the config API.  Only `send` has to be
+// implemented, the verbs are built on top of it, so a fake transport is
+// enough to unit test anything that reads or writes configuration.
 #[async_trait]
-pub trait Methods {
-    async fn get_data(&self, url: &str, auth_token: &str) -> Result<String, String>;
+pub trait Methods: Send + Sync {
+    // Send the request and return the response whatever its status
+    async fn send(&self, request: Request) -> Result<Response>;
+
+    // Same as `send`, with the body streamed.  By default the body is read
+    // in full by `send` first.
+    async fn send_streaming(&self, request: Request) -> Result<StreamingResponse> {
+        Ok(self.send(request).await?.into())
+    }
+
+    async fn get_data(&self, url: &Url, auth_token: &BearerToken) -> Result<String> {
+        let response = self.send(Request::get(url, auth_token)).await?;
+        Ok(response.error_for_status()?.body)
+    }
+
+    async fn post_json(
+        &self,
+        url: &Url,
+        auth_token: &BearerToken,
+        body: &Value,
+    ) -> Result<Response> {
+        self.send(Request::post(url, auth_token, body))
+            .await?
+            .error_for_status()
+    }
+
+    async fn put_json(
+        &self,
+        url: &Url,
+        auth_token: &BearerToken,
+        body: &Value,
+    ) -> Result<Response> {
+        self.send(Request::put(url, auth_token, body))
+            .await?
+            .error_for_status()
+    }
+
+    async fn patch_json(
+        &self,
+        url: &Url,
+        auth_token: &BearerToken,
+        body: &Value,
+    ) -> Result<Response> {
+        self.send(Request::patch(url, auth_token, body))
+            .await?
+            .error_for_status()
+    }
+
+    async fn delete(&self, url: &Url, auth_token: &BearerToken) -> Result<Response> {
+        self.send(Request::delete(url, auth_token))
+            .await?
+            .error_for_status()
+    }
+}
+
+pub struct Http {
+    pub client: HydrolixClient,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            client: HydrolixClient::shared(),
+        }
+    }
 }
 
-#[derive(Default)]
-pub struct Http {}
 #[async_trait]
 impl Methods for Http {
-    async fn get_data(&self, url: &str, auth_token: &str) -> Result<String, String> {
-        get_data(url, auth_token).await
+    async fn send(&self, request: Request) -> Result<Response> {
+        self.client.send(&request).await
+    }
+
+    async fn send_streaming(&self, request: Request) -> Result<StreamingResponse> {
+        let response = self.client.send_streaming(&request).await?;
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let url = request.url;
+        let error_url = url.to_string();
+        let body = response
+            .bytes_stream()
+            .map(move |v| match v {
+                Ok(v) => Ok(v.to_vec()),
+                Err(e) => Err(Error::Transport {
+                    url: error_url.to_string(),
+                    source: e,
+                }),
+            })
+            .boxed();
+        Ok(StreamingResponse {
+            url,
+            status,
+            headers,
+            body,
+        })
     }
 }
 
-pub async fn get_paginated(url: &str, auth_token: &str) -> Result<String, String> {
+pub async fn get_paginated<T: DeserializeOwned>(
+    url: &Url,
+    auth_token: &BearerToken,
+) -> Result<Vec<T>> {
     get_paginated_helper(url, auth_token, &Http::default()).await
 }
 
-// The signature mimics `http::get_data`.  The caller will deserialize the array of json themselves, so return a string
-pub async fn get_paginated_helper(
-    auth_token: &str,
-    url: &str,
+pub async fn get_paginated_with_retry<T: DeserializeOwned>(
+    url: &Url,
+    auth_token: &BearerToken,
+    retry: &RetryPolicy,
+) -> Result<Vec<T>> {
+    let client = HydrolixClient::shared().with_retry_policy(retry.clone());
+    get_paginated_with_client(&client, url, auth_token).await
+}
+
+pub async fn get_paginated_with_client<T: DeserializeOwned>(
+    client: &HydrolixClient,
+    url: &Url,
+    auth_token: &BearerToken,
+) -> Result<Vec<T>> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    get_paginated_helper(url, auth_token, &methods).await
+}
+
+// Yield items as each page arrives instead of waiting for the whole list
+pub fn get_paginated_stream<T: DeserializeOwned>(
+    client: &HydrolixClient,
+    url: &Url,
+    auth_token: &BearerToken,
+) -> impl Stream<Item = Result<T>> {
+    let methods = Box::new(Http {
+        client: client.clone(),
+    });
+    paginated_stream(url, auth_token, methods)
+}
+
+pub async fn get_paginated_helper<T: DeserializeOwned>(
+    url: &Url,
+    auth_token: &BearerToken,
     methods: &impl Methods,
-) -> Result<String, String> {
-    let mut page = 1;
-    let mut more_results = true;
-    let mut results = vec![];
-    while more_results {
-        let page_url = url.to_owned() + "?page=" + &page.to_string();
-
-        // Send the HTTP GET request to retrieve the list
-        let data = match methods.get_data(auth_token, &page_url).await {
-            Ok(v) => v,
-            Err(e) => return Err(format!("{}.{} Failed to execute: {e}", file!(), line!())),
+) -> Result<Vec<T>> {
+    paginated_stream(url, auth_token, methods)
+        .try_collect()
+        .await
+}
+
+// Walk the pages, following `next` until the last one.  `methods` is anything
+// that derefs to a transport, so both borrowed and owned transports work.
+pub fn paginated_stream<T, M, D>(
+    url: &Url,
+    auth_token: &BearerToken,
+    methods: M,
+) -> impl Stream<Item = Result<T>>
+where
+    T: DeserializeOwned,
+    M: Deref<Target = D>,
+    D: Methods + ?Sized,
+{
+    let first = Some(page_url(url.as_str(), 1));
+    // Pages already fetched, a `next` pointing back to one would never end
+    let seen: HashSet<String> = HashSet::new();
+    let state = (methods, auth_token.clone(), first, seen);
+    stream::try_unfold(state, |(methods, auth_token, page, mut seen)| async move {
+        let (page_url, page) = match page {
+            Some(v) => v?,
+            None => return Ok::<_, Error>(None),
         };
+        if !seen.insert(page_url.to_string()) {
+            return Err(Error::Response {
+                url: page_url,
+                message: "The next page loops back to a page already fetched".to_string(),
+            });
+        }
+        let data = methods.get_data(&Url::new(&page_url), &auth_token).await?;
+        let (items, next) = parse_page::<T>(&page_url, &data, page)?;
+        let items = stream::iter(items.into_iter().map(Ok));
+        Ok(Some((items, (methods, auth_token, next, seen))))
+    })
+    .try_flatten()
+}
+
+// A page is either a paginated object or, for endpoints that don't paginate, a plain array.
+// Returns the items and the URL of the next page, if any.
+#[allow(clippy::type_complexity)]
+fn parse_page<T: DeserializeOwned>(
+    url: &str,
+    data: &str,
+    page: u64,
+) -> Result<(Vec<T>, Option<Result<(String, u64)>>)> {
+    if data.trim_start().starts_with('[') {
+        return Ok((from_json(url, data)?, None));
+    }
+
+    let response: PaginatedResults<T> = from_json(url, data)?;
+    let next = match response.next {
+        None | Some(Next::Page(0)) => None,
+        Some(Next::Url(v)) if v.is_empty() => None,
+        Some(Next::Page(v)) if v <= page => None,
+        Some(Next::Page(v)) => Some(page_url(url, v)),
+        Some(Next::Url(v)) => Some(join_url(url, &v).map(|v| (v, page + 1))),
+    };
+    Ok((response.results, next))
+}
+
+fn parse_url(url: &str) -> Result<reqwest::Url> {
+    reqwest::Url::parse(url).map_err(|e| Error::Config(format!("Invalid url {url}: {e}")))
+}
+
+// Set the `page` query parameter, keeping any other parameters already in the URL
+pub fn page_url(url: &str, page: u64) -> Result<(String, u64)> {
+    let mut parsed = parse_url(url)?;
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(k, _)| k != "page")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    parsed
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("page", &page.to_string());
+    Ok((parsed.to_string(), page))
+}
+
+// `next` may be absolute or relative to the current page
+fn join_url(url: &str, next: &str) -> Result<String> {
+    match parse_url(url)?.join(next) {
+        Ok(v) => Ok(v.to_string()),
+        Err(e) => Err(Error::Response {
+            url: url.to_string(),
+            message: format!("Invalid next page {next}: {e}"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_paginated_helper, page_url, paginated_stream, BearerToken, Method, Methods, Request,
+        Url,
+    };
+    use crate::auth::HydrolixToken;
+    use crate::error::Error;
+    use crate::mock::MockMethods;
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        name: String,
+    }
+
+    fn url(v: &str) -> Url {
+        Url::new(v)
+    }
+
+    fn token() -> BearerToken {
+        BearerToken::new("t")
+    }
+
+    #[tokio::test]
+    async fn test_url_and_token_are_not_swapped() {
+        let methods = MockMethods::default()
+            .with_page("https://h/config/v1/orgs?page=1", r#"[{"name": "a"}]"#);
 
-        // Deserialize the response into the paginated results object
-        let response: PaginatedResults = match serde_json::from_str(&data) {
+        let mut token = HydrolixToken::new();
+        token.value = "secret".to_string();
+
+        let items: Vec<Item> = match get_paginated_helper(
+            &Url::new("https://h/config/v1/orgs"),
+            &BearerToken::from(&token),
+            &methods,
+        )
+        .await
+        {
             Ok(v) => v,
-            Err(_) => {
-                // If pagination deserialization fails, the response might _not_ be paginated, so return the raw response and the caller can try to deserialize the array
-                return Ok(data);
+            Err(e) => panic!("Failed to paginate: {e}"),
+        };
+        assert!(items.len() == 1);
+        let requests = methods.requests();
+        assert!(requests.len() == 1);
+        assert!(requests[0].url.as_str() == "https://h/config/v1/orgs?page=1");
+        assert!(requests[0].auth_token.as_str() == "secret");
+    }
+
+    #[tokio::test]
+    async fn test_paginated_forwards_token_on_every_page() {
+        let methods = MockMethods::default()
+            .with_page("https://h/items?page=1", r#"{"next": 2, "results": []}"#)
+            .with_page("https://h/items?page=2", r#"{"next": 0, "results": []}"#);
+
+        let items: Vec<Item> =
+            match get_paginated_helper(&url("https://h/items"), &token(), &methods).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to paginate: {e}"),
+            };
+        assert!(items.is_empty());
+        for request in methods.requests() {
+            assert!(request.url.as_str().starts_with("https://h/items?page="));
+            assert!(request.auth_token.as_str() == "t");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_verbs() {
+        let projects = "https://h/config/v1/orgs/o/projects/";
+        let project = "https://h/config/v1/orgs/o/projects/p/";
+        let methods = MockMethods::default()
+            .with_response(Method::POST, projects, 201, r#"{"uuid": "p"}"#)
+            .with_response(Method::PUT, project, 200, "{}")
+            .with_response(Method::PATCH, project, 400, r#"{"name": ["invalid"]}"#)
+            .with_response(Method::DELETE, project, 204, "");
+        let body = json!({"name": "demo"});
+
+        match methods.post_json(&url(projects), &token(), &body).await {
+            Ok(v) => {
+                assert!(v.status == 201);
+                match v.json::<serde_json::Value>() {
+                    Ok(v) => assert!(v["uuid"] == "p"),
+                    Err(e) => panic!("Failed to parse: {e}"),
+                }
+            }
+            Err(e) => panic!("Failed to post: {e}"),
+        }
+        assert!(methods
+            .put_json(&url(project), &token(), &body)
+            .await
+            .is_ok());
+        match methods.patch_json(&url(project), &token(), &body).await {
+            Ok(_) => panic!("A 400 should be an error"),
+            Err(e) => {
+                assert!(e.status() == Some(400));
+                assert!(e.to_string().contains("invalid"));
             }
+        }
+        assert!(methods.delete(&url(project), &token()).await.is_ok());
+
+        assert!(
+            methods.writes()
+                == [
+                    format!("POST {projects}"),
+                    format!("PUT {project}"),
+                    format!("PATCH {project}"),
+                    format!("DELETE {project}"),
+                ]
+        );
+        assert!(methods.requests()[0].body == Some(body));
+    }
+
+    #[test]
+    fn test_request_idempotency() {
+        let body = json!({});
+        assert!(Request::get(&url("https://h/"), &token()).is_idempotent());
+        assert!(Request::put(&url("https://h/"), &token(), &body).is_idempotent());
+        assert!(Request::delete(&url("https://h/"), &token()).is_idempotent());
+        assert!(!Request::post(&url("https://h/"), &token(), &body).is_idempotent());
+        assert!(!Request::patch(&url("https://h/"), &token(), &body).is_idempotent());
+    }
+
+    #[test]
+    fn test_bearer_token_debug() {
+        let token = BearerToken::new("secret");
+        assert!(!format!("{token:?}").contains("secret"));
+        assert!(token.as_str() == "secret");
+    }
+
+    #[test]
+    fn test_page_url() {
+        let check = |url: &str, expected: &str| match page_url(url, 2) {
+            Ok((v, page)) => assert!(v == expected && page == 2, "{v}"),
+            Err(e) => panic!("Failed to build page url: {e}"),
+        };
+        check(
+            "https://h/config/v1/orgs",
+            "https://h/config/v1/orgs?page=2",
+        );
+        check(
+            "https://h/config/v1/orgs/?name=a%20b",
+            "https://h/config/v1/orgs/?name=a+b&page=2",
+        );
+        check(
+            "https://h/config/v1/orgs?page=7&x=1",
+            "https://h/config/v1/orgs?x=1&page=2",
+        );
+        assert!(page_url("not a url", 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_paginated_page_numbers() {
+        let methods = MockMethods::default()
+            .with_page(
+                "https://h/items?page=1",
+                r#"{"next": 2, "count": 3, "results": [{"name": "a"}, {"name": "b"}]}"#,
+            )
+            .with_page(
+                "https://h/items?page=2",
+                r#"{"next": 0, "count": 3, "results": [{"name": "c"}]}"#,
+            );
+
+        let items: Vec<Item> =
+            match get_paginated_helper(&url("https://h/items"), &token(), &methods).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to paginate: {e}"),
+            };
+        let names: Vec<&str> = items.iter().map(|v| v.name.as_str()).collect();
+        assert!(names == ["a", "b", "c"]);
+        assert!(methods.requested_urls().len() == 2);
+    }
+
+    #[tokio::test]
+    async fn test_paginated_next_url() {
+        let methods = MockMethods::default()
+            .with_page(
+                "https://h/items?filter=x&page=1",
+                r#"{"next": "/items?filter=x&page=2", "results": [{"name": "a"}]}"#,
+            )
+            .with_page(
+                "https://h/items?filter=x&page=2",
+                r#"{"next": null, "results": [{"name": "b"}]}"#,
+            );
+
+        let items: Vec<Item> = match get_paginated_helper(
+            &url("https://h/items?filter=x"),
+            &token(),
+            &methods,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to paginate: {e}"),
         };
-        for item in response.results {
-            results.push(item)
+        assert!(items.len() == 2);
+        assert!(
+            methods.requested_urls()
+                == [
+                    "https://h/items?filter=x&page=1",
+                    "https://h/items?filter=x&page=2"
+                ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paginated_next_loop() {
+        let methods = MockMethods::default()
+            .with_page(
+                "https://h/items?page=1",
+                r#"{"next": "/items?page=2", "results": [{"name": "a"}]}"#,
+            )
+            .with_page(
+                "https://h/items?page=2",
+                r#"{"next": "/items?page=1", "results": [{"name": "b"}]}"#,
+            );
+
+        match get_paginated_helper::<Item>(&url("https://h/items"), &token(), &methods).await {
+            Ok(_) => panic!("A loop of pages should fail"),
+            Err(Error::Response { url, message }) => {
+                assert!(url == "https://h/items?page=1");
+                assert!(message.contains("loops back"));
+            }
+            Err(e) => panic!("Unexpected error {e}"),
         }
-        page += 1;
-        if response.next == 0 {
-            more_results = false;
+        assert!(methods.requested_urls().len() == 2);
+
+        // A `next` that isn't a URL is the fault of the response too
+        let methods = MockMethods::default().with_page(
+            "https://h/items?page=1",
+            r#"{"next": "https://[bad", "results": [{"name": "a"}]}"#,
+        );
+        match get_paginated_helper::<Item>(&url("https://h/items"), &token(), &methods).await {
+            Ok(_) => panic!("An invalid next page should fail"),
+            Err(e) => assert!(matches!(e, Error::Response { .. }) && e.url().is_some()),
         }
     }
-    match serde_json::to_string(&results) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(format!("{}.{} Failed to execute: {e}", file!(), line!())),
+
+    #[tokio::test]
+    async fn test_paginated_plain_array() {
+        let methods = MockMethods::default().with_page(
+            "https://h/items?page=1",
+            r#"[{"name": "a"}, {"name": "b"}]"#,
+        );
+
+        let items: Vec<Item> =
+            match get_paginated_helper(&url("https://h/items"), &token(), &methods).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to paginate: {e}"),
+            };
+        assert!(items.len() == 2);
+        assert!(methods.requested_urls().len() == 1);
+    }
+
+    #[tokio::test]
+    async fn test_paginated_stream() {
+        let methods = MockMethods::default()
+            .with_page(
+                "https://h/items?page=1",
+                r#"{"next": 2, "results": [{"name": "a"}]}"#,
+            )
+            .with_page(
+                "https://h/items?page=2",
+                r#"{"next": 0, "results": [{"name": 7}]}"#,
+            );
+
+        let mut stream = Box::pin(paginated_stream::<Item, _, _>(
+            &url("https://h/items"),
+            &token(),
+            &methods,
+        ));
+
+        // The first page is yielded before the second one is requested
+        match stream.next().await {
+            Some(Ok(v)) => assert!(v.name == "a"),
+            _ => panic!("Expected the first item"),
+        }
+        assert!(methods.requested_urls().len() == 1);
+
+        match stream.next().await {
+            Some(Err(Error::Deserialize { path, .. })) => assert!(path == "results[0].name"),
+            _ => panic!("Expected a deserialize error"),
+        }
+        assert!(stream.next().await.is_none());
     }
 }
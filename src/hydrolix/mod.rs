@@ -2,6 +2,7 @@ pub mod cluster;
 pub mod function;
 pub mod org;
 pub mod project;
+pub mod query_options;
 pub mod secrets;
 pub mod storage;
 pub mod table;
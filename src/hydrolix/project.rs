@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::hydrolix::function;
+use crate::hydrolix::query_options::{self, QueryOptions};
 use crate::hydrolix::table;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,9 +21,10 @@ pub struct Project {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
-    pub default_query_options: Value, // Generic JSON for flexibility
-    pub blob: Option<Value>,          // Nullable field for JSON blob
-    pub rate_limit: Option<Value>,    // Nullable field for rate limits
+    #[serde(default, deserialize_with = "query_options::or_default")]
+    pub default_query_options: QueryOptions,
+    pub blob: Option<Value>,       // Nullable field for JSON blob
+    pub rate_limit: Option<Value>, // Nullable field for rate limits
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -0,0 +1,354 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+// `default_query_options` of a project or table.  The cluster sends numbers
+// as numbers or strings, both are accepted.  Keys not known here, and known
+// keys with a value of an unexpected type, are kept in `extra`, so one odd
+// value never fails the parse of a whole project or table.  Values are
+// written back the way they were read, e.g. `"1000"` or `null`, unless they
+// were changed, so a dump applied to a cluster doesn't rewrite them.
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub hdx_query_max_execution_time: Option<u64>,
+    pub hdx_query_max_rows: Option<u64>,
+    pub hdx_query_max_result_rows: Option<u64>,
+    pub hdx_query_max_result_bytes: Option<u64>,
+    pub hdx_query_max_columns_to_read: Option<u64>,
+    pub hdx_query_max_timerange_sec: Option<u64>,
+    pub hdx_query_max_partitions: Option<u64>,
+    pub hdx_query_max_attempts: Option<u64>,
+    pub hdx_query_max_memory_usage: Option<u64>,
+    pub hdx_query_timerange_required: Option<bool>,
+    pub hdx_query_pool_name: Option<String>,
+    pub extra: BTreeMap<String, Value>,
+    // As parsed
+    raw: Map<String, Value>,
+}
+
+// Equal when the values are, whatever their JSON representation
+impl PartialEq for QueryOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_map() == other.to_map()
+    }
+}
+
+impl QueryOptions {
+    // `other` on top of `self`, a key set in both takes the value of `other`
+    pub fn merge(&self, other: &QueryOptions) -> QueryOptions {
+        let mut map = self.to_map();
+        map.extend(other.to_map());
+        QueryOptions::from_map(map)
+    }
+
+    // As query settings, e.g. `hdx_query_max_rows=1000`
+    pub fn to_settings(&self) -> BTreeMap<String, String> {
+        self.to_map()
+            .into_iter()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(k, v)| match v {
+                Value::String(v) => (k, v),
+                v => (k, v.to_string()),
+            })
+            .collect()
+    }
+
+    // The values that are set, numbers and bools as such
+    fn to_map(&self) -> Map<String, Value> {
+        let typed = [
+            (
+                "hdx_query_max_execution_time",
+                self.hdx_query_max_execution_time.map(Value::from),
+            ),
+            (
+                "hdx_query_max_rows",
+                self.hdx_query_max_rows.map(Value::from),
+            ),
+            (
+                "hdx_query_max_result_rows",
+                self.hdx_query_max_result_rows.map(Value::from),
+            ),
+            (
+                "hdx_query_max_result_bytes",
+                self.hdx_query_max_result_bytes.map(Value::from),
+            ),
+            (
+                "hdx_query_max_columns_to_read",
+                self.hdx_query_max_columns_to_read.map(Value::from),
+            ),
+            (
+                "hdx_query_max_timerange_sec",
+                self.hdx_query_max_timerange_sec.map(Value::from),
+            ),
+            (
+                "hdx_query_max_partitions",
+                self.hdx_query_max_partitions.map(Value::from),
+            ),
+            (
+                "hdx_query_max_attempts",
+                self.hdx_query_max_attempts.map(Value::from),
+            ),
+            (
+                "hdx_query_max_memory_usage",
+                self.hdx_query_max_memory_usage.map(Value::from),
+            ),
+            (
+                "hdx_query_timerange_required",
+                self.hdx_query_timerange_required.map(Value::from),
+            ),
+            (
+                "hdx_query_pool_name",
+                self.hdx_query_pool_name.clone().map(Value::from),
+            ),
+        ];
+        let mut map: Map<String, Value> = typed
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v?)))
+            .collect();
+        map.extend(self.extra.clone());
+        map
+    }
+
+    // `to_map`, with the unchanged values as they were parsed
+    fn to_raw_map(&self) -> Map<String, Value> {
+        let mut map = self.to_map();
+        for (key, value) in &self.raw {
+            let keep = match map.get(key) {
+                Some(v) => same(key, v, value),
+                // An explicit `null` of a value that is still unset
+                None => value.is_null(),
+            };
+            if keep {
+                map.insert(key.to_string(), value.clone());
+            }
+        }
+        map
+    }
+
+    fn from_map(map: Map<String, Value>) -> QueryOptions {
+        let mut options = QueryOptions {
+            raw: map.clone(),
+            ..QueryOptions::default()
+        };
+        for (key, value) in map {
+            let typed = match key.as_str() {
+                "hdx_query_max_execution_time" => {
+                    set(&mut options.hdx_query_max_execution_time, &value)
+                }
+                "hdx_query_max_rows" => set(&mut options.hdx_query_max_rows, &value),
+                "hdx_query_max_result_rows" => set(&mut options.hdx_query_max_result_rows, &value),
+                "hdx_query_max_result_bytes" => {
+                    set(&mut options.hdx_query_max_result_bytes, &value)
+                }
+                "hdx_query_max_columns_to_read" => {
+                    set(&mut options.hdx_query_max_columns_to_read, &value)
+                }
+                "hdx_query_max_timerange_sec" => {
+                    set(&mut options.hdx_query_max_timerange_sec, &value)
+                }
+                "hdx_query_max_partitions" => set(&mut options.hdx_query_max_partitions, &value),
+                "hdx_query_max_attempts" => set(&mut options.hdx_query_max_attempts, &value),
+                "hdx_query_max_memory_usage" => {
+                    set(&mut options.hdx_query_max_memory_usage, &value)
+                }
+                "hdx_query_timerange_required" => {
+                    set(&mut options.hdx_query_timerange_required, &value)
+                }
+                "hdx_query_pool_name" => set(&mut options.hdx_query_pool_name, &value),
+                _ => false,
+            };
+            if !typed {
+                options.extra.insert(key, value);
+            }
+        }
+        options
+    }
+}
+
+impl Serialize for QueryOptions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_raw_map().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryOptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(QueryOptions::from_map(Map::deserialize(deserializer)?))
+    }
+}
+
+// Whether two values of `key` parse the same, e.g. `"1000"` and `1000`
+fn same(key: &str, a: &Value, b: &Value) -> bool {
+    let parse = |v: &Value| {
+        let mut map = Map::new();
+        map.insert(key.to_string(), v.clone());
+        QueryOptions::from_map(map).to_map()
+    };
+    a == b || parse(a) == parse(b)
+}
+
+// Store `value` in `field` if it has the expected type, false if it doesn't
+fn set<T: DeserializeOwned>(field: &mut Option<T>, value: &Value) -> bool {
+    match lenient(value) {
+        Some(v) => {
+            *field = v;
+            true
+        }
+        None => false,
+    }
+}
+
+// A number or a bool, also when sent as a string.  `None` when `value` has
+// another type.
+fn lenient<T: DeserializeOwned>(value: &Value) -> Option<Option<T>> {
+    if value.is_null() {
+        return Some(None);
+    }
+    if let Ok(v) = serde_json::from_value(value.clone()) {
+        return Some(Some(v));
+    }
+    let value = match value {
+        Value::String(v) => serde_json::from_str(v.trim()).ok()?,
+        v => v.clone(),
+    };
+    match serde_json::from_value(value.clone()) {
+        Ok(v) => Some(Some(v)),
+        // `1` and `0` for bools
+        Err(_) => match value.as_u64() {
+            Some(v @ (0 | 1)) => serde_json::from_value(Value::Bool(v == 1)).ok().map(Some),
+            _ => None,
+        },
+    }
+}
+
+// `default_query_options` may be null or missing
+pub(crate) fn or_default<'de, D>(deserializer: D) -> Result<QueryOptions, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<QueryOptions>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryOptions;
+    use serde_json::json;
+
+    fn parse(value: serde_json::Value) -> QueryOptions {
+        match serde_json::from_value(value) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse(json!({
+            "hdx_query_max_rows": "1000",
+            "hdx_query_max_execution_time": 60,
+            "hdx_query_timerange_required": 1,
+            "hdx_query_pool_name": "reporting",
+            "max_threads": 4,
+        }));
+        assert!(options.hdx_query_max_rows == Some(1000));
+        assert!(options.hdx_query_max_execution_time == Some(60));
+        assert!(options.hdx_query_timerange_required == Some(true));
+        assert!(options.hdx_query_pool_name.as_deref() == Some("reporting"));
+        assert!(options.extra["max_threads"] == 4);
+
+        // Unknown keys survive a round trip, and strings stay strings
+        match serde_json::to_value(&options) {
+            Ok(v) => assert!(v["max_threads"] == 4 && v["hdx_query_max_rows"] == "1000"),
+            Err(e) => panic!("Failed to serialize: {e}"),
+        }
+
+        // Values of an unexpected type are kept instead of failing the parse
+        let options = parse(json!({
+            "hdx_query_max_rows": "x",
+            "hdx_query_max_attempts": -1,
+            "hdx_query_timerange_required": 2,
+            "hdx_query_max_partitions": 5,
+        }));
+        assert!(options.hdx_query_max_rows.is_none());
+        assert!(options.extra["hdx_query_max_rows"] == "x");
+        assert!(options.extra["hdx_query_max_attempts"] == -1);
+        assert!(options.extra["hdx_query_timerange_required"] == 2);
+        assert!(options.hdx_query_max_partitions == Some(5));
+        match serde_json::to_value(&options) {
+            Ok(v) => assert!(v["hdx_query_max_rows"] == "x" && v["hdx_query_max_attempts"] == -1),
+            Err(e) => panic!("Failed to serialize: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({
+            "hdx_query_max_rows": "1000",
+            "hdx_query_max_attempts": null,
+            "hdx_query_timerange_required": "1",
+            "hdx_query_pool_name": null,
+            "hdx_query_max_execution_time": 60,
+            "hdx_query_max_partitions": "x",
+            "max_threads": null,
+        });
+        let options = parse(value.clone());
+        assert!(options.hdx_query_max_rows == Some(1000));
+        assert!(options.hdx_query_timerange_required == Some(true));
+        match serde_json::to_value(&options) {
+            Ok(v) => assert!(v == value, "{v}"),
+            Err(e) => panic!("Failed to serialize: {e}"),
+        }
+        assert!(
+            options
+                == parse(
+                    json!({"hdx_query_max_rows": 1000, "hdx_query_max_execution_time": 60, "hdx_query_timerange_required": true, "hdx_query_max_partitions": "x", "max_threads": null})
+                )
+        );
+
+        // Changed values are written as they are now
+        let mut changed = options.clone();
+        changed.hdx_query_max_rows = Some(5);
+        changed.hdx_query_max_attempts = Some(2);
+        changed.hdx_query_timerange_required = None;
+        match serde_json::to_value(&changed) {
+            Ok(v) => {
+                assert!(v["hdx_query_max_rows"] == 5 && v["hdx_query_max_attempts"] == 2);
+                assert!(v.get("hdx_query_timerange_required").is_none());
+                assert!(v["hdx_query_pool_name"].is_null() && v["max_threads"].is_null());
+            }
+            Err(e) => panic!("Failed to serialize: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let project = parse(json!({"hdx_query_max_rows": 10, "hdx_query_max_attempts": 3}));
+        let table = parse(json!({"hdx_query_max_rows": 20, "max_threads": 2}));
+        let merged = project.merge(&table);
+        assert!(merged.hdx_query_max_rows == Some(20));
+        assert!(merged.hdx_query_max_attempts == Some(3));
+
+        let settings = merged.to_settings();
+        assert!(settings["hdx_query_max_rows"] == "20");
+        assert!(settings["max_threads"] == "2");
+        assert!(settings.len() == 3);
+
+        // Kept values aren't dropped by a merge, and still give way to `other`
+        let odd = parse(json!({"hdx_query_max_rows": "x", "hdx_query_max_attempts": 3}));
+        let merged = odd.merge(&parse(json!({"max_threads": 2})));
+        assert!(merged.extra["hdx_query_max_rows"] == "x");
+        assert!(merged.hdx_query_max_attempts == Some(3) && merged.extra["max_threads"] == 2);
+        let merged = odd.merge(&table);
+        assert!(merged.hdx_query_max_rows == Some(20));
+        assert!(!merged.extra.contains_key("hdx_query_max_rows"));
+        let merged = table.merge(&odd);
+        assert!(merged.hdx_query_max_rows.is_none() && merged.extra["hdx_query_max_rows"] == "x");
+    }
+}
@@ -1,4 +1,7 @@
 use serde::Deserialize;
+use std::fs;
+
+use crate::error::{Error, Result};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -6,6 +9,29 @@ pub struct Config {
     pub git_snapshots: GitSnapshots,
 }
 
+impl Config {
+    // Read and parse a secrets file such as `/tmp/fleet.secrets.toml`
+    pub fn load(path: &str) -> Result<Config> {
+        let content = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(Error::Io {
+                    path: path.to_string(),
+                    source: e,
+                })
+            }
+        };
+        Config::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Config> {
+        match toml::from_str(content) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::Config(format!("Failed to parse config: {e}"))),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Machine {
     pub base_url: String,
@@ -17,3 +43,25 @@ pub struct Machine {
 pub struct GitSnapshots {
     pub token: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::error::Error;
+
+    #[test]
+    fn test_load_errors() {
+        match Config::load("/nonexistent/fleet.secrets.toml") {
+            Err(Error::Io { path, source }) => {
+                assert!(path == "/nonexistent/fleet.secrets.toml");
+                assert!(source.kind() == std::io::ErrorKind::NotFound);
+            }
+            Ok(_) => panic!("A missing file should fail"),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+        assert!(matches!(
+            Config::parse("machines = 1"),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::hydrolix::query_options::{self, QueryOptions};
 use crate::hydrolix::transform::Transform;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,8 +21,9 @@ pub struct Table {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TableSettings {
-    pub default_query_options: Value, // Generic JSON for flexible key-value pairs
-    pub rate_limit: Option<Value>,    // Nullable field
+    #[serde(default, deserialize_with = "query_options::or_default")]
+    pub default_query_options: QueryOptions,
+    pub rate_limit: Option<Value>, // Nullable field
     pub stream: StreamSettings,
     pub age: AgeSettings,
     pub reaper: ReaperSettings,
@@ -31,6 +33,10 @@ pub struct TableSettings {
     pub shard_key: Option<Value>, // Nullable field
     pub max_future_days: i64,
     pub max_request_bytes: i64,
+    // Storages of the table by UUID: `default_storage_id`, and the keys of
+    // `column_value_mapping`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_map: Option<Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -0,0 +1,332 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::error::{Error, Result};
+use crate::http::{BearerToken, HeaderName, HeaderValue, Http, Method, Methods, Request, Url};
+use crate::hydrolix::project::Project;
+use crate::hydrolix::table::Table;
+use crate::hydrolix::transform::Transform;
+
+pub const TABLE_HEADER: &str = "x-hdx-table";
+pub const TRANSFORM_HEADER: &str = "x-hdx-transform";
+pub const STREAM_TOKEN_HEADER: &str = "x-hdx-token";
+
+// Where and how to send events: the table as `project.table`, the transform
+// that parses them, and the token to authenticate with
+#[derive(Debug, Clone)]
+pub struct Ingest {
+    pub base_url: String,
+    pub table: String,
+    // The default transform of the table when not set
+    pub transform: Option<String>,
+    // Known when built from a `Table`, to check transforms against
+    table_uuid: Option<String>,
+    auth_token: BearerToken,
+    // Sent instead of the bearer token when set
+    stream_token: Option<BearerToken>,
+}
+
+impl Ingest {
+    pub fn new(auth_token: &HydrolixToken, project: &str, table: &str) -> Ingest {
+        Ingest {
+            base_url: auth_token.base_url.to_string(),
+            table: format!("{project}.{table}"),
+            transform: None,
+            table_uuid: None,
+            auth_token: BearerToken::from(auth_token),
+            stream_token: None,
+        }
+    }
+
+    pub fn for_table(auth_token: &HydrolixToken, project: &Project, table: &Table) -> Ingest {
+        let mut ingest = Ingest::new(auth_token, &project.name, &table.name);
+        ingest.table_uuid = Some(table.uuid.to_string());
+        ingest
+    }
+
+    pub fn with_transform(mut self, name: &str) -> Self {
+        self.transform = Some(name.to_string());
+        self
+    }
+
+    // Fails when the transform belongs to another table.  Only checked for an
+    // `Ingest` built with `for_table`, `new` knows the table by name only.
+    pub fn with_transform_from(self, transform: &Transform) -> Result<Self> {
+        match &self.table_uuid {
+            Some(v) if *v != transform.table => Err(Error::Config(format!(
+                "Transform {} doesn't belong to table {}",
+                transform.name, self.table
+            ))),
+            _ => Ok(self.with_transform(&transform.name)),
+        }
+    }
+
+    // Authenticate with a stream token of the table instead of the login token
+    pub fn with_stream_token(mut self, token: &str) -> Self {
+        self.stream_token = Some(BearerToken::new(token));
+        self
+    }
+
+    // The first token of the table's `token_list`
+    pub fn with_stream_token_from(self, table: &Table) -> Result<Self> {
+        match table.settings.stream.token_list.first() {
+            Some(Value::String(v)) => Ok(self.with_stream_token(v)),
+            _ => Err(Error::Config(format!(
+                "Table {} has no stream token",
+                self.table
+            ))),
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::new(format!("https://{}/ingest/event", self.base_url))
+    }
+
+    fn request(&self) -> Result<Request> {
+        let token = match &self.stream_token {
+            Some(_) => BearerToken::new(""),
+            None => self.auth_token.clone(),
+        };
+        let mut request = Request::new(Method::POST, &self.url(), &token)
+            .with_header(header_name(TABLE_HEADER), header_value(&self.table, false)?);
+        if let Some(v) = &self.transform {
+            request = request.with_header(header_name(TRANSFORM_HEADER), header_value(v, false)?);
+        }
+        if let Some(v) = &self.stream_token {
+            request = request.with_header(
+                header_name(STREAM_TOKEN_HEADER),
+                header_value(v.as_str(), true)?,
+            );
+        }
+        Ok(request)
+    }
+}
+
+fn header_name(name: &'static str) -> HeaderName {
+    HeaderName::from_static(name)
+}
+
+// Sensitive values are hidden from `Debug` output
+fn header_value(value: &str, sensitive: bool) -> Result<HeaderValue> {
+    match HeaderValue::from_str(value) {
+        Ok(mut v) => {
+            v.set_sensitive(sensitive);
+            Ok(v)
+        }
+        Err(_) => Err(Error::Config(format!(
+            "Can't send {value:?} in an ingest header"
+        ))),
+    }
+}
+
+// Send a batch of events as a JSON array.  Ingest requests are never
+// retried, the cluster may already have taken the batch.
+pub async fn ingest_json<T: Serialize>(
+    client: &HydrolixClient,
+    ingest: &Ingest,
+    events: &[T],
+) -> Result<()> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    ingest_json_helper(&methods, ingest, events).await
+}
+
+pub async fn ingest_json_helper<T: Serialize>(
+    methods: &impl Methods,
+    ingest: &Ingest,
+    events: &[T],
+) -> Result<()> {
+    let body = match serde_json::to_value(events) {
+        Ok(v) => v,
+        Err(e) => return Err(Error::Serialize(format!("events as JSON: {e}"))),
+    };
+    let request = ingest.request()?.with_body(&body);
+    methods.send(request).await?.error_for_status()?;
+    Ok(())
+}
+
+// Send a batch of CSV lines, as expected by a CSV transform
+pub async fn ingest_csv(client: &HydrolixClient, ingest: &Ingest, csv: &str) -> Result<()> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    ingest_csv_helper(&methods, ingest, csv).await
+}
+
+pub async fn ingest_csv_helper(methods: &impl Methods, ingest: &Ingest, csv: &str) -> Result<()> {
+    let request = ingest.request()?.with_text(csv).with_header(
+        reqwest::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/csv"),
+    );
+    methods.send(request).await?.error_for_status()?;
+    Ok(())
+}
+
+// Rows serialized as CSV lines without a header, in field order
+pub fn to_csv<T: Serialize>(events: &[T]) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![]);
+    for event in events {
+        if let Err(e) = writer.serialize(event) {
+            return Err(Error::Serialize(format!("events as CSV: {e}")));
+        }
+    }
+    match writer.into_inner() {
+        Ok(v) => Ok(String::from_utf8_lossy(&v).to_string()),
+        Err(e) => Err(Error::Serialize(format!("events as CSV: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ingest_csv_helper, ingest_json_helper, to_csv, Ingest};
+    use crate::error::Error;
+    use crate::http::Method;
+    use crate::hydrolix::project::Project;
+    use crate::hydrolix::table::Table;
+    use crate::hydrolix::transform::Transform;
+    use crate::mock::{self, fixtures, MockMethods};
+    use serde::Serialize;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Event {
+        timestamp: String,
+        message: String,
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            Event {
+                timestamp: "2024-01-01T00:00:00Z".to_string(),
+                message: "hello, world".to_string(),
+            },
+            Event {
+                timestamp: "2024-01-01T00:00:01Z".to_string(),
+                message: "bye".to_string(),
+            },
+        ]
+    }
+
+    fn header<'a>(request: &'a crate::http::Request, name: &str) -> Option<&'a str> {
+        request.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[tokio::test]
+    async fn test_ingest_json() {
+        let methods =
+            MockMethods::default().with_response(Method::POST, "https://h/ingest/event", 200, "");
+        let ingest = Ingest::new(&mock::token(), "logs", "access").with_transform("json");
+        if let Err(e) = ingest_json_helper(&methods, &ingest, &events()).await {
+            panic!("Failed to ingest: {e}");
+        }
+
+        let requests = methods.requests();
+        assert!(header(&requests[0], "x-hdx-table") == Some("logs.access"));
+        assert!(header(&requests[0], "x-hdx-transform") == Some("json"));
+        assert!(requests[0].auth_token.as_str() == "t");
+        match &requests[0].body {
+            Some(v) => assert!(v[1]["message"] == "bye"),
+            None => panic!("No body"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest_csv_with_stream_token() {
+        let table: Table = match serde_json::from_value(fixtures::table("p1", "access", "t1")) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse table: {e}"),
+        };
+        let transform: Transform =
+            match serde_json::from_value(fixtures::transform("t1", "csv", "tx")) {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to parse transform: {e}"),
+            };
+        let project: Project = match serde_json::from_value(fixtures::project("o1", "logs", "p1")) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse project: {e}"),
+        };
+        let ingest = match Ingest::for_table(&mock::token(), &project, &table)
+            .with_transform_from(&transform)
+            .and_then(|v| v.with_stream_token_from(&table))
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to use stream token: {e}"),
+        };
+        // The token stays out of debug output
+        assert!(!format!("{ingest:?}").contains("stream-secret"));
+
+        let methods =
+            MockMethods::default().with_response(Method::POST, "https://h/ingest/event", 200, "");
+        let csv = match to_csv(&events()) {
+            Ok(v) => v,
+            Err(e) => panic!("events as CSV: {e}"),
+        };
+        assert!(csv.starts_with("2024-01-01T00:00:00Z,\"hello, world\"\n"));
+        if let Err(e) = ingest_csv_helper(&methods, &ingest, &csv).await {
+            panic!("Failed to ingest: {e}");
+        }
+
+        let requests = methods.requests();
+        assert!(header(&requests[0], "x-hdx-token") == Some("stream-secret"));
+        assert!(header(&requests[0], "x-hdx-transform") == Some("csv"));
+        assert!(header(&requests[0], "content-type") == Some("text/csv"));
+        assert!(requests[0].auth_token.as_str().is_empty());
+        assert!(!format!("{:?}", requests[0]).contains("stream-secret"));
+    }
+
+    #[tokio::test]
+    async fn test_ingest_errors() {
+        let methods = MockMethods::default().with_response(
+            Method::POST,
+            "https://h/ingest/event",
+            400,
+            "no such transform",
+        );
+        let ingest = Ingest::new(&mock::token(), "logs", "access");
+        match ingest_json_helper(&methods, &ingest, &[json!({"a": 1})]).await {
+            Ok(_) => panic!("The batch should be rejected"),
+            Err(e) => assert!(e.status() == Some(400)),
+        }
+
+        let mut table: Table = match serde_json::from_value(fixtures::table("p1", "t", "t1")) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse table: {e}"),
+        };
+        table.settings.stream.token_list.clear();
+        assert!(ingest.clone().with_stream_token_from(&table).is_err());
+
+        // A transform of another table
+        let transform: Transform =
+            match serde_json::from_value(fixtures::transform("t2", "csv", "tx")) {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to parse transform: {e}"),
+            };
+        let project: Project = match serde_json::from_value(fixtures::project("o1", "logs", "p1")) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse project: {e}"),
+        };
+        match Ingest::for_table(&mock::token(), &project, &table).with_transform_from(&transform) {
+            Ok(_) => panic!("The transform belongs to another table"),
+            Err(e) => assert!(e.to_string().contains("doesn't belong to table logs.t")),
+        }
+
+        // Events that can't be sent
+        match to_csv(&[json!({"nested": {"a": 1}})]) {
+            Ok(v) => panic!("Nested values aren't CSV: {v}"),
+            Err(Error::Serialize(_)) => (),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+        let events = [BTreeMap::from([(vec![1u8], 1)])];
+        match ingest_json_helper(&methods, &ingest, &events).await {
+            Ok(_) => panic!("Keys must be strings in JSON"),
+            Err(Error::Serialize(_)) => (),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+    }
+}
@@ -1,4 +1,20 @@
+pub mod apply;
 pub mod auth;
+pub mod client;
+pub mod clone;
+pub mod diff;
 pub mod dump;
+pub mod error;
+pub mod export;
+pub mod fleet;
 pub mod http;
 pub mod hydrolix;
+pub mod ingest;
+#[cfg(test)]
+mod mock;
+pub mod query;
+pub mod redact;
+pub mod retry;
+pub mod snapshot;
+
+pub use error::{Error, Result};
@@ -0,0 +1,394 @@
+// Fake transport for unit tests, so code that talks to the config API can be
+// exercised without a cluster.
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::http::{HeaderMap, Method, Methods, Request, Response, StreamingResponse};
+
+// Status and body, queued per method and URL
+type Responses = HashMap<(Method, String), VecDeque<(u16, String)>>;
+
+// Chunks of a streamed body per method and URL, and whether it breaks after
+// them instead of stalling
+type Streams = HashMap<(Method, String), (Vec<String>, bool)>;
+
+#[derive(Default)]
+pub struct MockMethods {
+    // The last queued response for a URL is repeated
+    responses: Mutex<Responses>,
+    streams: Mutex<Streams>,
+    // Requests that never get a response, e.g. a dropped connection
+    failures: Mutex<Vec<(Method, String)>>,
+    requests: Mutex<Vec<Request>>,
+    // Simulated latency, to observe how many requests run at once
+    delay: Option<Duration>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl MockMethods {
+    pub fn with_response(self, method: Method, url: &str, status: u16, body: &str) -> Self {
+        if let Ok(mut v) = self.responses.lock() {
+            v.entry((method, url.to_string()))
+                .or_default()
+                .push_back((status, body.to_string()));
+        }
+        self
+    }
+
+    // Replace whatever was queued for the URL
+    pub fn with_override(self, method: Method, url: &str, status: u16, body: &str) -> Self {
+        if let Ok(mut v) = self.responses.lock() {
+            v.remove(&(method.clone(), url.to_string()));
+        }
+        self.with_response(method, url, status, body)
+    }
+
+    // A 200 to `send_streaming` whose body yields `chunks` and then stalls,
+    // like a query that is still running
+    pub fn with_stream(self, method: Method, url: &str, chunks: &[&str]) -> Self {
+        self.insert_stream(method, url, chunks, false)
+    }
+
+    // A 200 to `send_streaming` whose body yields `chunks` and then fails
+    // with `Error::Transport`, like a connection that broke
+    pub fn with_broken_stream(self, method: Method, url: &str, chunks: &[&str]) -> Self {
+        self.insert_stream(method, url, chunks, true)
+    }
+
+    fn insert_stream(self, method: Method, url: &str, chunks: &[&str], broken: bool) -> Self {
+        if let Ok(mut v) = self.streams.lock() {
+            v.insert(
+                (method, url.to_string()),
+                (chunks.iter().map(|v| v.to_string()).collect(), broken),
+            );
+        }
+        self
+    }
+
+    // `send` fails with `Error::Transport` instead of answering
+    pub fn with_transport_error(self, method: Method, url: &str) -> Self {
+        if let Ok(mut v) = self.failures.lock() {
+            v.push((method, url.to_string()));
+        }
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn with_page(self, url: &str, body: &str) -> Self {
+        self.with_response(Method::GET, url, 200, body)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        match self.requests.lock() {
+            Ok(v) => v.clone(),
+            Err(e) => panic!("Poisoned: {e}"),
+        }
+    }
+
+    pub fn requested_urls(&self) -> Vec<String> {
+        self.requests().iter().map(|v| v.url.to_string()).collect()
+    }
+
+    // Keep the request, then wait for the simulated latency
+    async fn record(&self, request: &Request) {
+        if let Ok(mut v) = self.requests.lock() {
+            v.push(request.clone());
+        }
+
+        if let Some(delay) = self.delay {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Requests other than GET, as `METHOD url`
+    pub fn writes(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter(|v| v.method != Method::GET)
+            .map(|v| format!("{} {}", v.method, v.url))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Methods for MockMethods {
+    async fn send(&self, request: Request) -> Result<Response> {
+        self.record(&request).await;
+
+        let key = (request.method.clone(), request.url.to_string());
+        let failed = match self.failures.lock() {
+            Ok(v) => v.contains(&key),
+            Err(e) => panic!("Poisoned: {e}"),
+        };
+        if failed {
+            return Err(transport_error(request.url.as_str()));
+        }
+        let (status, body) = match self.responses.lock() {
+            Ok(mut v) => match v.get_mut(&key) {
+                Some(queue) if queue.len() > 1 => queue.pop_front().unwrap_or_default(),
+                Some(queue) => queue.front().cloned().unwrap_or_default(),
+                None => (404, String::new()),
+            },
+            Err(e) => panic!("Poisoned: {e}"),
+        };
+
+        Ok(Response {
+            url: request.url,
+            status,
+            headers: HeaderMap::new(),
+            body,
+        })
+    }
+
+    async fn send_streaming(&self, request: Request) -> Result<StreamingResponse> {
+        let key = (request.method.clone(), request.url.to_string());
+        let chunks = match self.streams.lock() {
+            Ok(v) => v.get(&key).cloned(),
+            Err(e) => panic!("Poisoned: {e}"),
+        };
+        let Some((chunks, broken)) = chunks else {
+            return Ok(self.send(request).await?.into());
+        };
+
+        self.record(&request).await;
+        let chunks = stream::iter(chunks.into_iter().map(|v| Ok(v.into_bytes())));
+        let body = if broken {
+            let url = request.url.to_string();
+            chunks
+                .chain(stream::once(async move { Err(transport_error(&url)) }))
+                .boxed()
+        } else {
+            chunks.chain(stream::pending()).boxed()
+        };
+        Ok(StreamingResponse {
+            url: request.url,
+            status: 200,
+            headers: HeaderMap::new(),
+            body,
+        })
+    }
+}
+
+// A real `reqwest::Error`, from a request that can't be built
+fn transport_error(url: &str) -> Error {
+    match reqwest::Client::new().get("not a url").build() {
+        Ok(_) => panic!("The request should not build"),
+        Err(e) => Error::Transport {
+            url: url.to_string(),
+            source: e,
+        },
+    }
+}
+
+// JSON as returned by the config API, for building fake clusters
+pub mod fixtures {
+    use serde_json::{json, Value};
+
+    pub fn storage(org: &str, name: &str, uuid: &str) -> Value {
+        json!({
+            "name": name,
+            "org": org,
+            "description": "",
+            "uuid": uuid,
+            "url": format!("https://h/config/v1/orgs/{org}/storages/{uuid}"),
+            "created": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-01T00:00:00Z",
+            "settings": {
+                "bucket_name": "bucket",
+                "bucket_path": "/",
+                "region": "us-east-1",
+                "endpoint": null,
+                "cloud": "aws",
+                "credential_id": "cred-1",
+                "account_name": null,
+                "is_default": true
+            },
+            "publish_task_id": null
+        })
+    }
+
+    pub fn project(org: &str, name: &str, uuid: &str) -> Value {
+        json!({
+            "name": name,
+            "org": org,
+            "description": null,
+            "uuid": uuid,
+            "url": format!("https://h/config/v1/orgs/{org}/projects/{uuid}"),
+            "created": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-01T00:00:00Z",
+            "settings": {
+                "default_query_options": {},
+                "blob": null,
+                "rate_limit": null
+            }
+        })
+    }
+
+    pub fn function(project: &str, name: &str, uuid: &str) -> Value {
+        json!({
+            "name": name,
+            "project": project,
+            "description": "",
+            "sql": "(x) -> x + 1",
+            "uuid": uuid,
+            "url": format!("https://h/functions/{uuid}"),
+            "created": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-01T00:00:00Z"
+        })
+    }
+
+    pub fn table(project: &str, name: &str, uuid: &str) -> Value {
+        json!({
+            "project": project,
+            "name": name,
+            "description": null,
+            "uuid": uuid,
+            "created": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-01T00:00:00Z",
+            "settings": {
+                "default_query_options": {},
+                "rate_limit": null,
+                "stream": {
+                    "token_list": ["stream-secret"],
+                    "hot_data_max_age_minutes": 3,
+                    "hot_data_max_active_partitions": 3,
+                    "hot_data_max_rows_per_partition": 12288000,
+                    "hot_data_max_minutes_per_partition": 1,
+                    "hot_data_max_open_seconds": 60,
+                    "hot_data_max_idle_seconds": 30,
+                    "cold_data_max_age_days": 3650,
+                    "cold_data_max_active_partitions": 50,
+                    "cold_data_max_rows_per_partition": 12288000,
+                    "cold_data_max_minutes_per_partition": 60,
+                    "cold_data_max_open_seconds": 300,
+                    "cold_data_max_idle_seconds": 60,
+                    "message_queue_max_rows": 0
+                },
+                "age": {"max_age_days": 0},
+                "reaper": {"max_age_days": 1},
+                "merge": {"enabled": true},
+                "autoingest": [{
+                    "enabled": false,
+                    "source": "",
+                    "source_region": "",
+                    "pattern": "",
+                    "max_rows_per_partition": 12288000,
+                    "max_minutes_per_partition": 60,
+                    "max_active_partitions": 50,
+                    "dry_run": false,
+                    "source_credential_id": null,
+                    "bucket_credential_id": null
+                }],
+                "sort_keys": [],
+                "shard_key": null,
+                "max_future_days": 0,
+                "max_request_bytes": 0
+            },
+            "url": format!("https://h/tables/{uuid}"),
+            "table_type": "turbine",
+            "primary_key": "timestamp"
+        })
+    }
+
+    pub fn transform(table: &str, name: &str, uuid: &str) -> Value {
+        json!({
+            "name": name,
+            "description": null,
+            "uuid": uuid,
+            "created": "2024-01-01T00:00:00Z",
+            "modified": "2024-01-01T00:00:00Z",
+            "settings": {
+                "is_default": true,
+                "rate_limit": null,
+                "null_values": null,
+                "sample_data": null,
+                "output_columns": [
+                    {"name": "timestamp", "datatype": {"type": "datetime", "index": false, "primary": true, "format": "2006-01-02 15:04:05", "resolution": "seconds", "default": null, "script": null, "source": null, "suppress": null}},
+                    {"name": "message", "datatype": {"type": "string", "index": true, "primary": null, "format": null, "resolution": null, "default": null, "script": null, "source": null, "suppress": null}}
+                ],
+                "compression": "none",
+                "wurfl": null,
+                "format_details": null
+            },
+            "url": format!("https://h/transforms/{uuid}"),
+            "type": "json",
+            "table": table
+        })
+    }
+
+    pub fn list(items: &[Value]) -> String {
+        Value::Array(items.to_vec()).to_string()
+    }
+}
+
+// A cluster `h` with one org `o1`, a storage, projects `p1` and `p2`, a
+// function in `p1`, tables `t1`/`t2` in `p1` and `t3` in `p2`, and one
+// transform per table
+pub fn cluster() -> MockMethods {
+    use fixtures::*;
+
+    let base = "https://h/config/v1/orgs/o1";
+    let mut methods = MockMethods::default()
+        .with_page(
+            &format!("{base}/storages?page=1"),
+            &list(&[storage("o1", "s1", "s1")]),
+        )
+        .with_page(
+            &format!("{base}/projects?page=1"),
+            &list(&[project("o1", "p1", "p1"), project("o1", "p2", "p2")]),
+        )
+        .with_page(
+            &format!("{base}/projects/p1/functions?page=1"),
+            &list(&[function("p1", "f1", "f1")]),
+        )
+        .with_page(&format!("{base}/projects/p2/functions?page=1"), "[]")
+        .with_page(
+            &format!("{base}/projects/p1/tables?page=1"),
+            &list(&[table("p1", "t1", "t1"), table("p1", "t2", "t2")]),
+        )
+        .with_page(
+            &format!("{base}/projects/p2/tables?page=1"),
+            &list(&[table("p2", "t3", "t3")]),
+        );
+    for (project, table) in [("p1", "t1"), ("p1", "t2"), ("p2", "t3")] {
+        methods = methods.with_page(
+            &format!("{base}/projects/{project}/tables/{table}/transforms/?page=1"),
+            &list(&[transform(
+                table,
+                &format!("{table}_transform"),
+                &format!("{table}x"),
+            )]),
+        );
+    }
+    methods
+}
+
+pub fn token() -> crate::auth::HydrolixToken {
+    let mut token = crate::auth::HydrolixToken::new();
+    token.base_url = "h".to_string();
+    token.value = "t".to_string();
+    token.org_list.push(crate::auth::Org {
+        uuid: "o1".to_string(),
+        name: "org1".to_string(),
+        cloud: "aws".to_string(),
+        kubernetes: true,
+    });
+    token
+}
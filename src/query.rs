@@ -0,0 +1,1455 @@
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::time::{timeout, Duration, Instant, Sleep};
+
+use crate::auth::HydrolixToken;
+use crate::client::HydrolixClient;
+use crate::error::{Error, Result};
+use crate::http::{self, BearerToken, HeaderMap, Http, Method, Methods, Request, Url};
+use crate::hydrolix::project::Project;
+use crate::hydrolix::query_options::QueryOptions;
+use crate::hydrolix::table::Table;
+
+// Output formats of the query API that rows can be decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    // One document with `meta`, `data` and statistics
+    #[default]
+    Json,
+    // One JSON object per line
+    JsonEachRow,
+    // No header line, rows are decoded by position
+    Csv,
+    // Tab separated with a header line, rows are decoded by column name
+    TsvWithNames,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::JsonEachRow => "JSONEachRow",
+            Format::Csv => "CSV",
+            Format::TsvWithNames => "TSVWithNames",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub sql: String,
+    pub format: Format,
+    // Sent as URL parameters, e.g. `hdx_query_max_execution_time`
+    pub settings: BTreeMap<String, String>,
+    // `default_query_options` of the queried project and table, `settings`
+    // win over them
+    pub defaults: QueryOptions,
+    // Project and table whose defaults are looked up before the query is
+    // sent, see `resolve`
+    pub table: Option<(String, String)>,
+    // Sent with the query, so it can be cancelled.  Random by default.
+    pub query_id: String,
+    // Cancel the query when it hasn't finished by then
+    pub deadline: Option<Duration>,
+}
+
+impl Query {
+    pub fn new(sql: &str) -> Query {
+        Query {
+            sql: sql.to_string(),
+            format: Format::default(),
+            settings: BTreeMap::new(),
+            defaults: QueryOptions::default(),
+            table: None,
+            query_id: new_query_id(),
+            deadline: None,
+        }
+    }
+
+    pub fn with_query_id(mut self, query_id: &str) -> Self {
+        self.query_id = query_id.to_string();
+        self
+    }
+
+    // Unlike `with_max_execution_time`, also covers the time spent sending
+    // the result.  The query is killed on the cluster when it passes.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // A handle to cancel the query from another task while it runs
+    pub fn cancel_handle(
+        &self,
+        client: &HydrolixClient,
+        auth_token: &HydrolixToken,
+    ) -> CancelHandle {
+        let methods = Http {
+            client: client.clone(),
+        };
+        CancelHandle::with_methods(Arc::new(methods), auth_token, &self.query_id)
+    }
+
+    // Layer `options` on top of the defaults set so far
+    pub fn with_defaults(mut self, options: &QueryOptions) -> Self {
+        self.defaults = self.defaults.merge(options);
+        self
+    }
+
+    // Project defaults, then table defaults
+    pub fn for_table(self, project: &Project, table: &Table) -> Self {
+        self.with_defaults(&project.settings.default_query_options)
+            .with_defaults(&table.settings.default_query_options)
+    }
+
+    // The query reads this table, its defaults and those of its project are
+    // looked up and applied when the query is sent
+    pub fn with_table(mut self, project: &str, table: &str) -> Self {
+        self.table = Some((project.to_string(), table.to_string()));
+        self
+    }
+
+    // The query with the defaults of its table applied, so that
+    // `effective_settings` shows what will be sent.  Defaults already set
+    // with `with_defaults` go on top of them.
+    pub async fn resolve(
+        &self,
+        client: &HydrolixClient,
+        auth_token: &HydrolixToken,
+    ) -> Result<Query> {
+        let methods = Http {
+            client: client.clone(),
+        };
+        self.resolve_helper(&methods, auth_token).await
+    }
+
+    pub async fn resolve_helper(
+        &self,
+        methods: &impl Methods,
+        auth_token: &HydrolixToken,
+    ) -> Result<Query> {
+        let mut query = self.clone();
+        if let Some((project, table)) = query.table.take() {
+            let defaults = table_defaults_helper(methods, auth_token, &project, &table).await?;
+            query.defaults = defaults.merge(&self.defaults);
+        }
+        Ok(query)
+    }
+
+    // The settings the query is sent with: the defaults, overridden by the
+    // settings of the query itself
+    pub fn effective_settings(&self) -> BTreeMap<String, String> {
+        let mut settings = self.defaults.to_settings();
+        settings.extend(self.settings.clone());
+        settings
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_setting(mut self, name: &str, value: impl ToString) -> Self {
+        self.settings.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    // Let the cluster stop the query after this long
+    pub fn with_max_execution_time(self, limit: Duration) -> Self {
+        self.with_setting("hdx_query_max_execution_time", limit.as_secs().max(1))
+    }
+
+    pub fn url(&self, base_url: &str) -> Result<Url> {
+        let mut url = match reqwest::Url::parse(&format!("https://{base_url}/query")) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::Config(format!("Invalid base URL {base_url}: {e}"))),
+        };
+        url.query_pairs_mut()
+            .append_pair("default_format", self.format.as_str())
+            .append_pair("query_id", &self.query_id)
+            .extend_pairs(&self.effective_settings());
+        Ok(Url::new(url.to_string()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    pub url: Url,
+    pub format: Format,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+struct JsonBody<T> {
+    data: Vec<T>,
+}
+
+// What follows the rows in the `JSON` format
+#[derive(Deserialize)]
+struct JsonSummary {
+    rows: Option<u64>,
+    statistics: Option<BTreeMap<String, Value>>,
+}
+
+// Statistics of a query, as sent by the cluster in the `X-HDX-Query-Stats`
+// or `X-ClickHouse-Summary` headers and, for the `JSON` format, in the
+// summary after the rows.  Which values are there depends on the cluster.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    pub values: BTreeMap<String, String>,
+}
+
+impl QueryStats {
+    pub fn from_headers(headers: &HeaderMap) -> QueryStats {
+        let mut stats = QueryStats::default();
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        // `rows_read=10 bytes_read=2048 exec_time=12 ...`
+        if let Some(v) = header("x-hdx-query-stats") {
+            for pair in v.split(|c: char| c.is_whitespace() || c == ',' || c == ';') {
+                if let Some((name, value)) = pair.split_once('=') {
+                    stats.insert(name, value);
+                }
+            }
+        }
+        // `{"read_rows":"10","read_bytes":"2048","elapsed_ns":"12000000"}`
+        if let Some(v) = header("x-clickhouse-summary") {
+            if let Ok(summary) = serde_json::from_str::<BTreeMap<String, Value>>(v) {
+                for (name, value) in summary {
+                    let name = match name.as_str() {
+                        "read_rows" => "rows_read",
+                        "read_bytes" => "bytes_read",
+                        v => v,
+                    };
+                    stats.insert(name, &value_to_string(&value));
+                }
+            }
+        }
+        if let Some(v) = header("x-clickhouse-query-id") {
+            stats.insert("query_id", v);
+        }
+        stats
+    }
+
+    fn insert(&mut self, name: &str, value: &str) {
+        self.values
+            .insert(name.trim().to_string(), value.trim().to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| v.as_str())
+    }
+
+    fn number(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    pub fn rows_read(&self) -> Option<u64> {
+        self.number("rows_read").map(|v| v as u64)
+    }
+
+    pub fn bytes_read(&self) -> Option<u64> {
+        self.number("bytes_read").map(|v| v as u64)
+    }
+
+    pub fn query_id(&self) -> Option<&str> {
+        self.get("query_id")
+    }
+
+    // `elapsed` is in seconds, `elapsed_ns` in nanoseconds and `exec_time`
+    // in milliseconds
+    pub fn elapsed(&self) -> Option<Duration> {
+        let seconds = match (
+            self.number("elapsed"),
+            self.number("elapsed_ns"),
+            self.number("exec_time"),
+        ) {
+            (Some(v), _, _) => v,
+            (None, Some(v), _) => v / 1e9,
+            (None, None, Some(v)) => v / 1e3,
+            (None, None, None) => return None,
+        };
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.to_string(),
+        v => v.to_string(),
+    }
+}
+
+impl QueryResult {
+    // Statistics from the headers, and from the summary of the `JSON` format
+    pub fn stats(&self) -> QueryStats {
+        let mut stats = QueryStats::from_headers(&self.headers);
+        if self.format != Format::Json {
+            return stats;
+        }
+        if let Ok(summary) = serde_json::from_str::<JsonSummary>(&self.body) {
+            if let Some(v) = summary.rows {
+                stats.insert("rows", &v.to_string());
+            }
+            for (name, value) in summary.statistics.unwrap_or_default() {
+                stats.insert(&name, &value_to_string(&value));
+            }
+        }
+        stats
+    }
+
+    // Decode the rows, whatever the format of the query
+    pub fn rows<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        let url = self.url.as_str();
+        match self.format {
+            Format::Json => Ok(http::from_json::<JsonBody<T>>(url, &self.body)?.data),
+            Format::JsonEachRow => self
+                .body
+                .lines()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| http::from_json(url, v))
+                .collect(),
+            Format::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(self.body.as_bytes());
+                let rows: std::result::Result<Vec<T>, csv::Error> = reader.deserialize().collect();
+                rows.map_err(|e| csv_error(url, e))
+            }
+            Format::TsvWithNames => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(b'\t')
+                    .quoting(false)
+                    .from_reader(self.body.as_bytes());
+                let headers = match reader.headers() {
+                    Ok(v) => v.clone(),
+                    Err(e) => return Err(csv_error(url, e)),
+                };
+                let mut rows = vec![];
+                for record in reader.records() {
+                    let record = match record {
+                        Ok(v) => v,
+                        Err(e) => return Err(csv_error(url, e)),
+                    };
+                    let unescaped: csv::StringRecord = record.iter().map(unescape_tsv).collect();
+                    match unescaped.deserialize(Some(&headers)) {
+                        Ok(v) => rows.push(v),
+                        Err(e) => return Err(csv_error(url, e)),
+                    }
+                }
+                Ok(rows)
+            }
+        }
+    }
+}
+
+fn csv_error(url: &str, source: csv::Error) -> Error {
+    Error::Csv {
+        url: url.to_string(),
+        source,
+    }
+}
+
+// TSV escapes tabs, newlines and backslashes with a backslash
+fn unescape_tsv(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some(v) => out.push(v),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+pub async fn query(auth_token: &HydrolixToken, query: &Query) -> Result<QueryResult> {
+    query_with_client(&HydrolixClient::shared(), auth_token, query).await
+}
+
+// The query is killed on the cluster when the future is dropped or the
+// deadline of the query passes
+pub async fn query_with_client(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<QueryResult> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    query_guarded_helper(Arc::new(methods), auth_token, query).await
+}
+
+pub async fn query_guarded_helper<M: Methods + 'static>(
+    methods: Arc<M>,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<QueryResult> {
+    // Before the deadline starts, nothing runs on the cluster yet
+    let query = &query.resolve_helper(&*methods, auth_token).await?;
+    let mut guard = KillOnDrop::new(CancelHandle::with_methods(
+        methods.clone(),
+        auth_token,
+        &query.query_id,
+    ));
+    let result = match query.deadline {
+        Some(deadline) => match timeout(deadline, query_helper(&*methods, auth_token, query)).await
+        {
+            Ok(v) => v,
+            // The guard kills the query on the way out
+            Err(_) => {
+                return Err(Error::Timeout {
+                    url: query.url(&auth_token.base_url)?.to_string(),
+                    after: deadline,
+                })
+            }
+        },
+        None => query_helper(&*methods, auth_token, query).await,
+    };
+    // Without an answer of the cluster, e.g. on a dropped connection, the
+    // query may still be running and the guard kills it
+    if matches!(result, Ok(_) | Err(Error::Status { .. })) {
+        guard.disarm();
+    }
+    result
+}
+
+// Cancels a running query by its ID
+#[derive(Clone)]
+pub struct CancelHandle {
+    methods: Arc<dyn Methods>,
+    base_url: String,
+    auth_token: BearerToken,
+    query_id: String,
+}
+
+impl std::fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("base_url", &self.base_url)
+            .field("query_id", &self.query_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CancelHandle {
+    pub fn new(client: &HydrolixClient, auth_token: &HydrolixToken, query_id: &str) -> Self {
+        let methods = Http {
+            client: client.clone(),
+        };
+        CancelHandle::with_methods(Arc::new(methods), auth_token, query_id)
+    }
+
+    pub fn with_methods(
+        methods: Arc<dyn Methods>,
+        auth_token: &HydrolixToken,
+        query_id: &str,
+    ) -> Self {
+        CancelHandle {
+            methods,
+            base_url: auth_token.base_url.to_string(),
+            auth_token: BearerToken::from(auth_token),
+            query_id: query_id.to_string(),
+        }
+    }
+
+    pub fn query_id(&self) -> &str {
+        &self.query_id
+    }
+
+    // Kill the query on the cluster.  Killing a query that already finished
+    // is not an error.
+    pub async fn cancel(&self) -> Result<()> {
+        let url = Url::new(format!("https://{}/query", self.base_url));
+        let id = self.query_id.replace('\\', "\\\\").replace('\'', "\\'");
+        let sql = format!("KILL QUERY WHERE query_id = '{id}'");
+        let request = Request::new(Method::POST, &url, &self.auth_token).with_text(&sql);
+        self.methods.send(request).await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+// Kills the query when dropped before `disarm`, from a background task as
+// `Drop` can't wait
+struct KillOnDrop {
+    handle: Option<CancelHandle>,
+}
+
+impl KillOnDrop {
+    fn new(handle: CancelHandle) -> Self {
+        KillOnDrop {
+            handle: Some(handle),
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.handle = None;
+    }
+
+    fn kill(&mut self) {
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = handle.cancel().await;
+            });
+        }
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+fn new_query_id() -> String {
+    format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..))
+}
+
+// Run the query and decode the rows into `T`
+pub async fn query_rows<T: DeserializeOwned>(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<Vec<T>> {
+    query_with_client(client, auth_token, query).await?.rows()
+}
+
+// The merged `default_query_options` of a table, looked up by project and
+// table name in the orgs of the token
+pub async fn table_defaults(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    project: &str,
+    table: &str,
+) -> Result<QueryOptions> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    table_defaults_helper(&methods, auth_token, project, table).await
+}
+
+pub async fn table_defaults_helper(
+    methods: &impl Methods,
+    auth_token: &HydrolixToken,
+    project: &str,
+    table: &str,
+) -> Result<QueryOptions> {
+    let bearer = BearerToken::from(auth_token);
+    for o in &auth_token.org_list {
+        let url = Url::new(format!(
+            "https://{}/config/v1/orgs/{}/projects",
+            auth_token.base_url, o.uuid
+        ));
+        let projects: Vec<Project> = http::get_paginated_helper(&url, &bearer, methods).await?;
+        let Some(p) = projects.iter().find(|v| v.name == project) else {
+            continue;
+        };
+
+        let url = Url::new(format!("{url}/{}/tables", p.uuid));
+        let tables: Vec<Table> = http::get_paginated_helper(&url, &bearer, methods).await?;
+        let Some(t) = tables.iter().find(|v| v.name == table) else {
+            return Err(Error::Config(format!(
+                "Table {project}.{table} does not exist on {}",
+                auth_token.base_url
+            )));
+        };
+        return Ok(p
+            .settings
+            .default_query_options
+            .merge(&t.settings.default_query_options));
+    }
+    Err(Error::Config(format!(
+        "Project {project} does not exist on {}",
+        auth_token.base_url
+    )))
+}
+
+// Rows of a query decoded one at a time as the body comes in.  The body is
+// only read as fast as the rows are consumed.
+pub struct RowStream<T> {
+    pub url: Url,
+    // From the headers, the body of a streamed query has no summary
+    pub stats: QueryStats,
+    rows: BoxStream<'static, Result<T>>,
+    // Until the last row is read, dropping the stream kills the query
+    guard: KillOnDrop,
+    deadline: Option<(Pin<Box<Sleep>>, Duration)>,
+    finished: bool,
+    // The body broke, so the end of the stream isn't the end of the query
+    broken: bool,
+}
+
+impl<T> std::fmt::Debug for RowStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RowStream")
+            .field("url", &self.url)
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Stream for RowStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.finished {
+            return Poll::Ready(None);
+        }
+        if let Some((sleep, after)) = &mut this.deadline {
+            if sleep.as_mut().poll(cx).is_ready() {
+                let after = *after;
+                this.finished = true;
+                this.guard.kill();
+                return Poll::Ready(Some(Err(Error::Timeout {
+                    url: this.url.to_string(),
+                    after,
+                })));
+            }
+        }
+        match this.rows.poll_next_unpin(cx) {
+            Poll::Ready(None) => {
+                this.finished = true;
+                if this.broken {
+                    this.guard.kill();
+                } else {
+                    this.guard.disarm();
+                }
+                Poll::Ready(None)
+            }
+            // Rows that don't fit `T` are errors too, anything else came
+            // from the body
+            Poll::Ready(Some(Err(e))) => {
+                this.broken = !matches!(e, Error::Deserialize { .. } | Error::Csv { .. });
+                Poll::Ready(Some(Err(e)))
+            }
+            v => v,
+        }
+    }
+}
+
+// Run a `JSONEachRow` or `CSV` query and stream its rows.  Errors of the
+// cluster are returned before any row.
+pub async fn query_stream<T: DeserializeOwned + Send + 'static>(
+    client: &HydrolixClient,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<RowStream<T>> {
+    let methods = Http {
+        client: client.clone(),
+    };
+    query_stream_helper(Arc::new(methods), auth_token, query).await
+}
+
+pub async fn query_stream_helper<T: DeserializeOwned + Send + 'static, M: Methods + 'static>(
+    methods: Arc<M>,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<RowStream<T>> {
+    if !matches!(query.format, Format::JsonEachRow | Format::Csv) {
+        return Err(Error::Config(format!(
+            "Can't stream rows of the {} format, use JSONEachRow or CSV",
+            query.format.as_str()
+        )));
+    }
+    let query = &query.resolve_helper(&*methods, auth_token).await?;
+    let url = query.url(&auth_token.base_url)?;
+    let request =
+        Request::new(Method::POST, &url, &BearerToken::from(auth_token)).with_text(&query.sql);
+    let mut guard = KillOnDrop::new(CancelHandle::with_methods(
+        methods.clone(),
+        auth_token,
+        &query.query_id,
+    ));
+    let started = Instant::now();
+    let response = match query.deadline {
+        Some(deadline) => match timeout(deadline, methods.send_streaming(request)).await {
+            Ok(v) => v?,
+            // The guard kills the query on the way out
+            Err(_) => {
+                return Err(Error::Timeout {
+                    url: url.to_string(),
+                    after: deadline,
+                })
+            }
+        },
+        None => methods.send_streaming(request).await?,
+    };
+
+    let stats = QueryStats::from_headers(&response.headers);
+    if !(200..300).contains(&response.status) {
+        guard.disarm();
+        let body: Vec<u8> = response
+            .body
+            .filter_map(|v| async move { v.ok() })
+            .concat()
+            .await;
+        return Err(Error::Status {
+            url: url.to_string(),
+            status: response.status,
+            body: String::from_utf8_lossy(&body).to_string(),
+        });
+    }
+
+    Ok(RowStream {
+        rows: decode_stream(&url, query.format, response.body),
+        url,
+        stats,
+        guard,
+        deadline: query
+            .deadline
+            .map(|v| (Box::pin(tokio::time::sleep_until(started + v)), v)),
+        finished: false,
+        broken: false,
+    })
+}
+
+// Splits bytes into records: lines, or for CSV newlines outside of quotes
+#[derive(Debug, Default)]
+struct Records {
+    buffer: Vec<u8>,
+    // Start of the next record, and how far it has been looked at
+    start: usize,
+    scanned: usize,
+    csv: bool,
+    in_quotes: bool,
+}
+
+impl Records {
+    fn push(&mut self, chunk: &[u8]) {
+        self.buffer.drain(..self.start);
+        self.scanned -= self.start;
+        self.start = 0;
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        while self.scanned < self.buffer.len() {
+            let byte = self.buffer[self.scanned];
+            self.scanned += 1;
+            if self.csv && byte == b'"' {
+                // A `""` inside quotes flips twice
+                self.in_quotes = !self.in_quotes;
+            } else if byte == b'\n' && !self.in_quotes {
+                let record = self.buffer[self.start..self.scanned].to_vec();
+                self.start = self.scanned;
+                return Some(record);
+            }
+        }
+        None
+    }
+
+    // The last record when the body doesn't end with a newline
+    fn rest(&mut self) -> Option<Vec<u8>> {
+        if self.start == self.buffer.len() {
+            return None;
+        }
+        let record = self.buffer[self.start..].to_vec();
+        self.start = self.buffer.len();
+        self.scanned = self.start;
+        Some(record)
+    }
+}
+
+struct Decoder<B> {
+    bytes: BoxStream<'static, Result<B>>,
+    records: Records,
+    url: Url,
+    format: Format,
+    done: bool,
+}
+
+fn decode_stream<T, B, S>(url: &Url, format: Format, bytes: S) -> BoxStream<'static, Result<T>>
+where
+    T: DeserializeOwned + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    S: Stream<Item = Result<B>> + Send + 'static,
+{
+    let decoder = Decoder {
+        bytes: bytes.boxed(),
+        records: Records {
+            csv: format == Format::Csv,
+            ..Records::default()
+        },
+        url: url.clone(),
+        format,
+        done: false,
+    };
+    stream::unfold(decoder, |mut d| async move {
+        loop {
+            let record = match d.records.next() {
+                Some(v) => v,
+                None if d.done => d.records.rest()?,
+                None => {
+                    match d.bytes.next().await {
+                        Some(Ok(chunk)) => d.records.push(chunk.as_ref()),
+                        Some(Err(e)) => {
+                            // Nothing after a broken body can be trusted
+                            d.done = true;
+                            d.records = Records::default();
+                            return Some((Err(e), d));
+                        }
+                        None => d.done = true,
+                    }
+                    continue;
+                }
+            };
+            if let Some(row) = decode_record(&d.url, d.format, &record) {
+                return Some((row, d));
+            }
+        }
+    })
+    .boxed()
+}
+
+// `None` for blank lines
+fn decode_record<T: DeserializeOwned>(
+    url: &Url,
+    format: Format,
+    record: &[u8],
+) -> Option<Result<T>> {
+    if record.iter().all(|v| v.is_ascii_whitespace()) {
+        return None;
+    }
+    match format {
+        Format::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(record);
+            let row = reader.deserialize().next()?;
+            Some(row.map_err(|e| csv_error(url.as_str(), e)))
+        }
+        _ => Some(http::from_json(
+            url.as_str(),
+            &String::from_utf8_lossy(record),
+        )),
+    }
+}
+
+pub async fn query_helper(
+    methods: &impl Methods,
+    auth_token: &HydrolixToken,
+    query: &Query,
+) -> Result<QueryResult> {
+    let query = &query.resolve_helper(methods, auth_token).await?;
+    let url = query.url(&auth_token.base_url)?;
+    let request =
+        Request::new(Method::POST, &url, &BearerToken::from(auth_token)).with_text(&query.sql);
+    let response = methods.send(request).await?.error_for_status()?;
+    Ok(QueryResult {
+        url: response.url,
+        format: query.format,
+        headers: response.headers,
+        body: response.body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_stream, query_guarded_helper, query_helper, query_stream, query_stream_helper,
+        table_defaults_helper, unescape_tsv, CancelHandle, Format, Query, QueryResult, QueryStats,
+    };
+    use crate::client::HydrolixClient;
+    use crate::error::{Error, Result};
+    use crate::http::{HeaderMap, Method, Url};
+    use crate::hydrolix::query_options::QueryOptions;
+    use crate::mock::{self, fixtures, MockMethods};
+    use futures::{stream, StreamExt};
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::time::Duration;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Row {
+        host: String,
+        hits: u64,
+    }
+
+    fn result(format: Format, body: &str) -> QueryResult {
+        QueryResult {
+            url: Url::new("https://h/query"),
+            format,
+            headers: HeaderMap::new(),
+            body: body.to_string(),
+        }
+    }
+
+    fn expected() -> Vec<Row> {
+        vec![
+            Row {
+                host: "a\tb".to_string(),
+                hits: 3,
+            },
+            Row {
+                host: "c".to_string(),
+                hits: 4,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_url() {
+        let query = Query::new("SELECT 1")
+            .with_format(Format::JsonEachRow)
+            .with_query_id("q1")
+            .with_max_execution_time(Duration::from_secs(30))
+            .with_setting("hdx_query_max_rows", 1000);
+        match query.url("h") {
+            Ok(v) => assert!(
+                v.as_str()
+                    == "https://h/query?default_format=JSONEachRow&query_id=q1&hdx_query_max_execution_time=30&hdx_query_max_rows=1000"
+            ),
+            Err(e) => panic!("Failed to build URL: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_rows() {
+        let bodies = [
+            (
+                Format::Json,
+                r#"{"meta": [], "data": [{"host": "a\tb", "hits": 3}, {"host": "c", "hits": 4}], "rows": 2}"#,
+            ),
+            (
+                Format::JsonEachRow,
+                "{\"host\": \"a\\tb\", \"hits\": 3}\n{\"host\": \"c\", \"hits\": 4}\n",
+            ),
+            (Format::Csv, "\"a\tb\",3\n\"c\",4\n"),
+            (Format::TsvWithNames, "hits\thost\n3\ta\\tb\n4\tc\n"),
+        ];
+        for (format, body) in bodies {
+            match result(format, body).rows::<Row>() {
+                Ok(v) => assert!(v == expected(), "{format:?}: {v:?}"),
+                Err(e) => panic!("Failed to decode {format:?}: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rows_type_mismatch() {
+        match result(Format::Csv, "a,not-a-number\n").rows::<Row>() {
+            Ok(_) => panic!("hits is not a number"),
+            Err(Error::Csv { url, .. }) => assert!(url == "https://h/query"),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+        match result(Format::Json, r#"{"data": [{"host": "a", "hits": "3"}]}"#).rows::<Row>() {
+            Ok(_) => panic!("hits is a string"),
+            Err(Error::Deserialize { path, .. }) => assert!(path == "data[0].hits"),
+            Err(e) => panic!("Unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn test_unescape_tsv() {
+        assert!(unescape_tsv("a\\\\b\\nc") == "a\\b\nc");
+        assert!(unescape_tsv("plain") == "plain");
+    }
+
+    #[tokio::test]
+    async fn test_query_helper() {
+        let url = "https://h/query?default_format=JSONEachRow&query_id=q1";
+        let methods = MockMethods::default().with_response(
+            Method::POST,
+            url,
+            200,
+            "{\"host\": \"c\", \"hits\": 4}\n",
+        );
+        let query = Query::new("SELECT host, count() AS hits FROM logs GROUP BY host")
+            .with_format(Format::JsonEachRow)
+            .with_query_id("q1");
+        let rows: Vec<Row> = match query_helper(&methods, &mock::token(), &query).await {
+            Ok(v) => match v.rows() {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to decode: {e}"),
+            },
+            Err(e) => panic!("Failed to query: {e}"),
+        };
+        assert!(rows.len() == 1 && rows[0].hits == 4);
+
+        let requests = methods.requests();
+        assert!(requests[0].text.as_deref() == Some(query.sql.as_str()));
+        assert!(requests[0].auth_token.as_str() == "t");
+
+        // Errors from the cluster keep their body
+        let methods = MockMethods::default().with_response(Method::POST, url, 400, "Syntax error");
+        match query_helper(&methods, &mock::token(), &query).await {
+            Ok(_) => panic!("The query should fail"),
+            Err(e) => assert!(e.status() == Some(400) && e.to_string().contains("Syntax error")),
+        }
+    }
+
+    fn chunks(parts: &[&str]) -> Vec<Result<Vec<u8>>> {
+        parts.iter().map(|v| Ok(v.as_bytes().to_vec())).collect()
+    }
+
+    async fn collect(format: Format, parts: &[&str]) -> Vec<Result<Row>> {
+        let url = Url::new("https://h/query");
+        decode_stream(&url, format, stream::iter(chunks(parts)))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_stream_rows() {
+        // Records split across chunks, a blank line and no final newline
+        let rows = collect(
+            Format::JsonEachRow,
+            &[
+                "{\"host\": \"a\\tb\", \"hi",
+                "ts\": 3}\n\n{\"host\": \"c\",",
+                " \"hits\": 4}",
+            ],
+        )
+        .await;
+        let rows: Vec<Row> = rows.into_iter().filter_map(|v| v.ok()).collect();
+        assert!(rows == expected());
+
+        // A quoted CSV field with a newline in it
+        let rows = collect(Format::Csv, &["\"a\nb\",3\n\"c", "\"\"d\",4\n"]).await;
+        let rows: Vec<Row> = rows.into_iter().filter_map(|v| v.ok()).collect();
+        assert!(rows.len() == 2);
+        assert!(rows[0].host == "a\nb" && rows[1].host == "c\"d");
+    }
+
+    #[tokio::test]
+    async fn test_stream_errors() {
+        // A bad row doesn't end the stream
+        let rows = collect(Format::Csv, &["a,x\nc,4\n"]).await;
+        assert!(rows.len() == 2);
+        assert!(matches!(rows[0], Err(Error::Csv { .. })));
+        assert!(rows[1].is_ok());
+
+        // A broken body does
+        let url = Url::new("https://h/query");
+        let parts = vec![
+            Ok(b"a,1\nb,".to_vec()),
+            Err(Error::Config("connection reset".to_string())),
+            Ok(b"2\n".to_vec()),
+        ];
+        let rows: Vec<Result<Row>> = decode_stream(&url, Format::Csv, stream::iter(parts))
+            .collect()
+            .await;
+        assert!(rows.len() == 2);
+        assert!(rows[0].is_ok() && rows[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_backpressure() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let parts = stream::iter(0..1000).map(move |i| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("h{i},{i}\n").into_bytes())
+        });
+        let url = Url::new("https://h/query");
+        let mut rows = decode_stream::<Row, _, _>(&url, Format::Csv, parts);
+        for _ in 0..3 {
+            assert!(matches!(rows.next().await, Some(Ok(_))));
+        }
+        assert!(pulled.load(Ordering::SeqCst) == 3);
+    }
+
+    #[tokio::test]
+    async fn test_stream_format() {
+        let query = Query::new("SELECT 1");
+        let client = HydrolixClient::new();
+        match query_stream::<Row>(&client, &mock::token(), &query).await {
+            Ok(_) => panic!("JSON can't be streamed"),
+            Err(e) => assert!(matches!(e, Error::Config(_))),
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-hdx-query-stats",
+            "rows_read=10 bytes_read=2048 exec_time=12"
+                .parse()
+                .unwrap_or_else(|e| panic!("Bad header: {e}")),
+        );
+        let stats = QueryStats::from_headers(&headers);
+        assert!(stats.rows_read() == Some(10));
+        assert!(stats.bytes_read() == Some(2048));
+        assert!(stats.elapsed() == Some(Duration::from_millis(12)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-clickhouse-summary",
+            r#"{"read_rows":"5","read_bytes":"100","elapsed_ns":"2000000"}"#
+                .parse()
+                .unwrap_or_else(|e| panic!("Bad header: {e}")),
+        );
+        headers.insert(
+            "x-clickhouse-query-id",
+            "q-1".parse().unwrap_or_else(|e| panic!("Bad header: {e}")),
+        );
+        let stats = QueryStats::from_headers(&headers);
+        assert!(stats.rows_read() == Some(5));
+        assert!(stats.elapsed() == Some(Duration::from_millis(2)));
+        assert!(stats.query_id() == Some("q-1"));
+
+        // The summary after the rows of the JSON format
+        let result = result(
+            Format::Json,
+            r#"{"meta": [], "data": [], "rows": 0, "statistics": {"elapsed": 0.5, "rows_read": 7, "bytes_read": 70}}"#,
+        );
+        let stats = result.stats();
+        assert!(stats.get("rows") == Some("0"));
+        assert!(stats.rows_read() == Some(7));
+        assert!(stats.elapsed() == Some(Duration::from_millis(500)));
+    }
+
+    #[tokio::test]
+    async fn test_table_defaults() {
+        let base = "https://h/config/v1/orgs/o1";
+        let mut project = fixtures::project("o1", "logs", "p1");
+        project["settings"]["default_query_options"] =
+            json!({"hdx_query_max_rows": "100", "hdx_query_max_attempts": 3});
+        let mut table = fixtures::table("p1", "access", "t1");
+        table["settings"]["default_query_options"] =
+            json!({"hdx_query_max_rows": 200, "hdx_query_max_execution_time": 60});
+        let methods = MockMethods::default()
+            .with_page(
+                &format!("{base}/projects?page=1"),
+                &fixtures::list(&[project]),
+            )
+            .with_page(
+                &format!("{base}/projects/p1/tables?page=1"),
+                &fixtures::list(&[table]),
+            );
+
+        let defaults = match table_defaults_helper(&methods, &mock::token(), "logs", "access").await
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to get defaults: {e}"),
+        };
+        assert!(defaults.hdx_query_max_rows == Some(200));
+        assert!(defaults.hdx_query_max_attempts == Some(3));
+
+        // The query's own settings win, and can be looked at before sending
+        let query = Query::new("SELECT 1")
+            .with_defaults(&defaults)
+            .with_max_execution_time(Duration::from_secs(5));
+        let settings = query.effective_settings();
+        assert!(settings["hdx_query_max_execution_time"] == "5");
+        assert!(settings["hdx_query_max_rows"] == "200");
+        assert!(settings["hdx_query_max_attempts"] == "3");
+        match query.url("h") {
+            Ok(v) => assert!(v.as_str().contains("hdx_query_max_rows=200")),
+            Err(e) => panic!("Failed to build URL: {e}"),
+        }
+
+        match table_defaults_helper(&methods, &mock::token(), "logs", "missing").await {
+            Ok(_) => panic!("The table does not exist"),
+            Err(e) => assert!(e.to_string().contains("logs.missing")),
+        }
+        assert!(Query::new("SELECT 1").defaults == QueryOptions::default());
+    }
+
+    #[tokio::test]
+    async fn test_query_with_table() {
+        let base = "https://h/config/v1/orgs/o1";
+        let mut project = fixtures::project("o1", "logs", "p1");
+        project["settings"]["default_query_options"] =
+            json!({"hdx_query_max_rows": "100", "hdx_query_max_attempts": 3});
+        let mut table = fixtures::table("p1", "access", "t1");
+        table["settings"]["default_query_options"] =
+            json!({"hdx_query_max_rows": 200, "hdx_query_max_execution_time": 60});
+        let methods = MockMethods::default()
+            .with_page(
+                &format!("{base}/projects?page=1"),
+                &fixtures::list(&[project]),
+            )
+            .with_page(
+                &format!("{base}/projects/p1/tables?page=1"),
+                &fixtures::list(&[table]),
+            );
+
+        // Project, then table, then the query's own settings
+        let query = Query::new("SELECT count() FROM logs.access")
+            .with_format(Format::Csv)
+            .with_query_id("q")
+            .with_table("logs", "access")
+            .with_max_execution_time(Duration::from_secs(5));
+        assert!(query.effective_settings().len() == 1);
+        let resolved = match query.resolve_helper(&methods, &mock::token()).await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to resolve: {e}"),
+        };
+        let settings = resolved.effective_settings();
+        assert!(settings["hdx_query_max_rows"] == "200");
+        assert!(settings["hdx_query_max_attempts"] == "3");
+        assert!(settings["hdx_query_max_execution_time"] == "5");
+        assert!(resolved.table.is_none());
+
+        // Sending the query applies them without being asked
+        let url = match resolved.url("h") {
+            Ok(v) => v.to_string(),
+            Err(e) => panic!("Failed to build URL: {e}"),
+        };
+        let methods = Arc::new(methods.with_response(Method::POST, &url, 200, "a,1\n"));
+        match query_guarded_helper(methods.clone(), &mock::token(), &query).await {
+            Ok(v) => assert!(v.body == "a,1\n"),
+            Err(e) => panic!("Failed to query: {e}"),
+        }
+        match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+            Ok(v) => assert!(v.collect::<Vec<_>>().await.len() == 1),
+            Err(e) => panic!("Failed to stream: {e}"),
+        }
+        let sent: Vec<String> = methods
+            .requested_urls()
+            .into_iter()
+            .filter(|v| v.starts_with("https://h/query"))
+            .collect();
+        assert!(sent == vec![url.to_string(), url]);
+
+        // An unknown table fails before anything is sent
+        let query = query.with_table("logs", "missing");
+        match query_guarded_helper(methods.clone(), &mock::token(), &query).await {
+            Ok(_) => panic!("The table does not exist"),
+            Err(e) => assert!(e.to_string().contains("logs.missing")),
+        }
+    }
+
+    fn kills(methods: &MockMethods) -> Vec<String> {
+        methods
+            .requests()
+            .iter()
+            .filter_map(|v| v.text.clone())
+            .filter(|v| v.starts_with("KILL QUERY"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_cancel_handle() {
+        let methods = Arc::new(MockMethods::default().with_response(
+            Method::POST,
+            "https://h/query",
+            200,
+            "",
+        ));
+        let handle = CancelHandle::with_methods(methods.clone(), &mock::token(), "it's");
+        if let Err(e) = handle.cancel().await {
+            panic!("Failed to cancel: {e}");
+        }
+        assert!(kills(&methods) == vec!["KILL QUERY WHERE query_id = 'it\\'s'"]);
+
+        // Two queries never share an ID
+        assert!(Query::new("SELECT 1").query_id != Query::new("SELECT 1").query_id);
+    }
+
+    // Kill requests are sent from a background task, give it a chance to run
+    async fn killed(methods: &MockMethods) -> Vec<String> {
+        for _ in 0..100 {
+            if !kills(methods).is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        kills(methods)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_kills_query() {
+        let methods = Arc::new(MockMethods::default().with_delay(Duration::from_secs(5)));
+        let query = Query::new("SELECT sleep(60)")
+            .with_query_id("slow")
+            .with_deadline(Duration::from_millis(50));
+        match query_guarded_helper(methods.clone(), &mock::token(), &query).await {
+            Ok(_) => panic!("The query should time out"),
+            Err(e) => assert!(matches!(e, Error::Timeout { .. }) && e.is_timeout()),
+        }
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'slow'"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_kills_query() {
+        let methods = Arc::new(MockMethods::default().with_delay(Duration::from_secs(5)));
+        let query = Query::new("SELECT sleep(60)").with_query_id("dropped");
+        let token = mock::token();
+        let future = query_guarded_helper(methods.clone(), &token, &query);
+        assert!(tokio::time::timeout(Duration::from_millis(50), future)
+            .await
+            .is_err());
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'dropped'"]);
+
+        // A query that finishes is left alone
+        let methods = Arc::new(MockMethods::default());
+        let query = Query::new("SELECT 1").with_deadline(Duration::from_secs(5));
+        let _ = query_guarded_helper(methods.clone(), &mock::token(), &query).await;
+        assert!(killed(&methods).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transport_error_kills_query() {
+        let query = Query::new("SELECT 1").with_query_id("lost");
+        let url = match query.url("h") {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to build URL: {e}"),
+        };
+        let methods = Arc::new(
+            MockMethods::default()
+                .with_transport_error(Method::POST, url.as_str())
+                .with_response(Method::POST, "https://h/query", 200, ""),
+        );
+        match query_guarded_helper(methods.clone(), &mock::token(), &query).await {
+            Ok(_) => panic!("The query should fail"),
+            Err(e) => assert!(matches!(e, Error::Transport { .. })),
+        }
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'lost'"]);
+    }
+
+    fn streamed(query_id: &str) -> String {
+        format!("https://h/query?default_format=CSV&query_id={query_id}")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_drop_kills_query() {
+        let methods = Arc::new(
+            MockMethods::default()
+                .with_stream(Method::POST, &streamed("partial"), &["a,1\nb,2\n"])
+                .with_response(Method::POST, "https://h/query", 200, ""),
+        );
+        let query = Query::new("SELECT host, hits FROM logs")
+            .with_format(Format::Csv)
+            .with_query_id("partial");
+        let mut rows =
+            match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to stream: {e}"),
+            };
+        assert!(matches!(rows.next().await, Some(Ok(_))));
+        assert!(killed(&methods).await.is_empty());
+
+        drop(rows);
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'partial'"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_deadline_kills_query() {
+        // The deadline passes while rows are coming in
+        let methods = Arc::new(
+            MockMethods::default()
+                .with_stream(Method::POST, &streamed("stalled"), &["a,1\n"])
+                .with_response(Method::POST, "https://h/query", 200, ""),
+        );
+        let query = Query::new("SELECT host, hits FROM logs")
+            .with_format(Format::Csv)
+            .with_query_id("stalled")
+            .with_deadline(Duration::from_secs(1));
+        let mut rows =
+            match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to stream: {e}"),
+            };
+        assert!(matches!(rows.next().await, Some(Ok(_))));
+        match rows.next().await {
+            Some(Err(e)) => assert!(e.is_timeout()),
+            v => panic!("The stream should time out: {v:?}"),
+        }
+        assert!(rows.next().await.is_none());
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'stalled'"]);
+
+        // The deadline passes before the cluster answers
+        let methods = Arc::new(MockMethods::default().with_delay(Duration::from_secs(5)));
+        let query = query.with_query_id("sending");
+        match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+            Ok(_) => panic!("The query should time out"),
+            Err(e) => assert!(matches!(e, Error::Timeout { .. })),
+        }
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'sending'"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_broken_kills_query() {
+        let methods = Arc::new(
+            MockMethods::default()
+                .with_broken_stream(Method::POST, &streamed("broken"), &["a,1\n"])
+                .with_response(Method::POST, &streamed("bad_row"), 200, "a,x\n")
+                .with_response(Method::POST, "https://h/query", 200, ""),
+        );
+        let query = Query::new("SELECT host, hits FROM logs")
+            .with_format(Format::Csv)
+            .with_query_id("broken");
+        let rows =
+            match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to stream: {e}"),
+            };
+        let rows: Vec<Result<Row>> = rows.collect().await;
+        assert!(rows.len() == 2 && rows[0].is_ok());
+        assert!(matches!(rows[1], Err(Error::Transport { .. })));
+        assert!(killed(&methods).await == vec!["KILL QUERY WHERE query_id = 'broken'"]);
+
+        // A row that doesn't fit is not the end of the query
+        let query = query.with_query_id("bad_row");
+        let rows =
+            match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to stream: {e}"),
+            };
+        let rows: Vec<Result<Row>> = rows.collect().await;
+        assert!(rows.len() == 1 && matches!(rows[0], Err(Error::Csv { .. })));
+        assert!(killed(&methods).await.len() == 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_to_the_end() {
+        let methods = Arc::new(MockMethods::default().with_response(
+            Method::POST,
+            &streamed("done"),
+            200,
+            "a,1\nb,2\n",
+        ));
+        let query = Query::new("SELECT host, hits FROM logs")
+            .with_format(Format::Csv)
+            .with_query_id("done")
+            .with_deadline(Duration::from_secs(5));
+        let rows =
+            match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to stream: {e}"),
+            };
+        let rows: Vec<Result<Row>> = rows.collect().await;
+        assert!(rows.len() == 2 && rows.iter().all(|v| v.is_ok()));
+        assert!(killed(&methods).await.is_empty());
+
+        // Errors of the cluster come before any row, and don't kill
+        let methods = Arc::new(MockMethods::default().with_response(
+            Method::POST,
+            &streamed("done"),
+            400,
+            "Syntax error",
+        ));
+        match query_stream_helper::<Row, _>(methods.clone(), &mock::token(), &query).await {
+            Ok(_) => panic!("The query should fail"),
+            Err(e) => assert!(e.status() == Some(400) && e.to_string().contains("Syntax error")),
+        }
+        assert!(killed(&methods).await.is_empty());
+    }
+}
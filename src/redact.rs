@@ -0,0 +1,274 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::hydrolix::cluster;
+
+// Where each kind of resource sits in a serialized cluster.  `*` is every
+// element of a list.
+const LOCATIONS: [(&str, &str); 7] = [
+    ("cluster", ""),
+    ("org", "/orgs/*"),
+    ("storage", "/orgs/*/storages/*"),
+    ("project", "/orgs/*/projects/*"),
+    ("function", "/orgs/*/projects/*/functions/*"),
+    ("table", "/orgs/*/projects/*/tables/*"),
+    ("transform", "/orgs/*/projects/*/tables/*/transforms/*"),
+];
+
+// Start of every redacted value
+pub const REDACTED_PREFIX: &str = "redacted:sha256:";
+
+// Fields known to carry secrets
+const BUILT_IN_RULES: [(&str, &str); 4] = [
+    ("table", "/settings/stream/token_list"),
+    ("table", "/settings/autoingest/*/source_credential_id"),
+    ("table", "/settings/autoingest/*/bucket_credential_id"),
+    ("storage", "/settings/credential_id"),
+];
+
+// A field to redact: a JSON pointer, relative to every resource of a kind,
+// e.g. `table` and `/settings/default_query_options/password`.  A `*`
+// segment matches every element of a list or every key of an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub resource: String,
+    pub pointer: String,
+}
+
+// Replaces secrets with a stable hash such as `redacted:sha256:3f2a...`, so a
+// diff of two redacted dumps still shows that a secret changed.  Set a salt
+// so short secrets can't be found by hashing guesses.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    salt: String,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor {
+            rules: BUILT_IN_RULES
+                .iter()
+                .map(|(resource, pointer)| Rule {
+                    resource: resource.to_string(),
+                    pointer: pointer.to_string(),
+                })
+                .collect(),
+            salt: String::new(),
+        }
+    }
+}
+
+impl Redactor {
+    // No rules at all, not even the built-in ones
+    pub fn empty() -> Redactor {
+        Redactor {
+            rules: vec![],
+            salt: String::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, resource: &str, pointer: &str) -> Self {
+        self.rules.push(Rule {
+            resource: resource.to_string(),
+            pointer: pointer.to_string(),
+        });
+        self
+    }
+
+    pub fn with_salt(mut self, salt: &str) -> Self {
+        self.salt = salt.to_string();
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn redact(&self, cluster: &cluster::Cluster) -> Result<cluster::Cluster> {
+        let mut value = match serde_json::to_value(cluster) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::Serialize(format!("cluster to redact: {e}"))),
+        };
+
+        for rule in &self.rules {
+            let Some((_, location)) = LOCATIONS.iter().find(|(v, _)| *v == rule.resource) else {
+                return Err(Error::Config(format!(
+                    "Unknown resource {} in redaction rule",
+                    rule.resource
+                )));
+            };
+            if !rule.pointer.is_empty() && !rule.pointer.starts_with('/') {
+                return Err(Error::Config(format!(
+                    "Invalid JSON pointer {} in redaction rule",
+                    rule.pointer
+                )));
+            }
+            let pointer = format!("{location}{}", rule.pointer);
+            let segments: Vec<String> = pointer.split('/').skip(1).map(unescape).collect();
+            self.apply(&mut value, &segments);
+        }
+
+        // A rule on a field that isn't a string, e.g. a number, can't be read
+        // back into the model
+        match serde_json::from_value(value) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::Config(format!(
+                "Redaction left a field of the wrong type: {e}"
+            ))),
+        }
+    }
+
+    fn apply(&self, value: &mut Value, segments: &[String]) {
+        let Some((first, rest)) = segments.split_first() else {
+            self.replace(value);
+            return;
+        };
+        match value {
+            Value::Array(items) if first == "*" => {
+                for v in items {
+                    self.apply(v, rest);
+                }
+            }
+            Value::Object(map) if first == "*" => {
+                for v in map.values_mut() {
+                    self.apply(v, rest);
+                }
+            }
+            Value::Array(items) => {
+                if let Some(v) = first.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                    self.apply(v, rest);
+                }
+            }
+            Value::Object(map) => {
+                if let Some(v) = map.get_mut(first) {
+                    self.apply(v, rest);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Hash every leaf, so each token of a list keeps its own hash.  Nulls
+    // hide nothing and are kept.
+    fn replace(&self, value: &mut Value) {
+        match value {
+            Value::Null => (),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.replace(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.replace(v)),
+            _ => *value = Value::String(hash_secret(&self.salt, value)),
+        }
+    }
+}
+
+// Redact with the built-in rules
+pub fn redact(cluster: &cluster::Cluster) -> Result<cluster::Cluster> {
+    Redactor::default().redact(cluster)
+}
+
+pub fn hash_secret(salt: &str, value: &Value) -> String {
+    // Strings without their JSON quotes
+    let text = match value {
+        Value::String(v) => v.to_string(),
+        v => v.to_string(),
+    };
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(text)
+        .finalize();
+    let hex: String = digest.iter().take(8).map(|v| format!("{v:02x}")).collect();
+    format!("{REDACTED_PREFIX}{hex}")
+}
+
+// Whether `value` was written by `hash_secret`
+pub fn is_redacted(value: &str) -> bool {
+    value.starts_with(REDACTED_PREFIX)
+}
+
+// `~1` is `/` and `~0` is `~` in a JSON pointer
+fn unescape(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_secret, redact, Redactor};
+    use crate::dump::{dump_helper, DumpOptions};
+    use crate::error::Error;
+    use crate::hydrolix::cluster::Cluster;
+    use crate::mock;
+    use serde_json::json;
+
+    async fn mock_cluster() -> Cluster {
+        match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+            Ok((v, _)) => *v,
+            Err(e) => panic!("Failed to dump: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        let a = hash_secret("", &json!("stream-secret"));
+        assert!(a == hash_secret("", &json!("stream-secret")));
+        assert!(a != hash_secret("salt", &json!("stream-secret")));
+        assert!(a.starts_with("redacted:sha256:") && a.len() == 32);
+    }
+
+    #[tokio::test]
+    async fn test_built_in_rules() {
+        let cluster = mock_cluster().await;
+        let redacted = match redact(&cluster) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to redact: {e}"),
+        };
+        let text = match serde_json::to_string(&redacted) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to serialize: {e}"),
+        };
+        assert!(!text.contains("stream-secret"));
+        assert!(!text.contains("cred-1"));
+
+        let orgs = redacted.orgs.unwrap_or_default();
+        let storage = &orgs[0].storages.as_deref().unwrap_or_default()[0];
+        let expected = hash_secret("", &json!("cred-1"));
+        assert!(storage.settings.credential_id.as_deref() == Some(expected.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_user_rules() {
+        let mut cluster = mock_cluster().await;
+        if let Some(orgs) = &mut cluster.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects[0].settings.default_query_options =
+                    serde_json::from_value(json!({"user": "reader", "pass/word": "hunter2"}))
+                        .unwrap_or_default();
+            }
+        }
+
+        let redactor =
+            Redactor::empty().with_rule("project", "/settings/default_query_options/pass~1word");
+        let redacted = match redactor.redact(&cluster) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to redact: {e}"),
+        };
+        let orgs = redacted.orgs.as_deref().unwrap_or_default();
+        let options = &orgs[0].projects.as_deref().unwrap_or_default()[0]
+            .settings
+            .default_query_options;
+        assert!(options.extra["user"] == "reader");
+        assert!(options.extra["pass/word"] == hash_secret("", &json!("hunter2")));
+
+        // The built-in rules are off with `empty()`
+        let text = serde_json::to_string(&redacted).unwrap_or_default();
+        assert!(text.contains("stream-secret"));
+
+        // A number can't be replaced by a hash
+        let bad = Redactor::empty().with_rule("table", "/settings/max_future_days");
+        assert!(matches!(bad.redact(&cluster), Err(Error::Config(_))));
+        assert!(Redactor::empty()
+            .with_rule("bucket", "/x")
+            .redact(&cluster)
+            .is_err());
+    }
+}
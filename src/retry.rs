@@ -0,0 +1,311 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response};
+use std::fmt;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+// Status codes worth retrying by default: rate limiting and gateway errors
+pub const DEFAULT_RETRYABLE_STATUSES: [u16; 4] = [429, 502, 503, 504];
+
+// A request about to be sent again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+    pub url: String,
+    // 1 for the first retry
+    pub retry: u32,
+    pub delay: Duration,
+    // The status code or transport error that caused the retry
+    pub reason: String,
+}
+
+pub type RetryHook = Arc<dyn Fn(&Retry) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one
+    pub max_attempts: u32,
+    // Backoff before the first retry, doubled for every retry after that
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Randomize the backoff so many clients don't retry in lockstep
+    pub jitter: bool,
+    pub retryable_statuses: Vec<u16>,
+    // Never wait longer than this for a `Retry-After` header
+    pub max_retry_after: Duration,
+    // Called before every retry, for logging and metrics
+    pub on_retry: Option<RetryHook>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("retryable_statuses", &self.retryable_statuses)
+            .field("max_retry_after", &self.max_retry_after)
+            .field("on_retry", &self.on_retry.is_some())
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            retryable_statuses: DEFAULT_RETRYABLE_STATUSES.to_vec(),
+            max_retry_after: Duration::from_secs(120),
+            on_retry: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Single attempt, no retries
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_retryable_statuses(mut self, statuses: &[u16]) -> Self {
+        self.retryable_statuses = statuses.to_vec();
+        self
+    }
+
+    pub fn with_on_retry(mut self, hook: impl Fn(&Retry) + Send + Sync + 'static) -> Self {
+        self.on_retry = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    // Connection failures, resets and timeouts are transient
+    pub fn is_retryable_error(&self, e: &reqwest::Error) -> bool {
+        e.is_connect() || e.is_timeout() || e.is_request()
+    }
+
+    // Delay before retry number `retry` (0 based).  With jitter the delay is
+    // picked between half and all of the exponential backoff.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        let half = backoff / 2;
+        let spread = (backoff - half).as_millis() as u64;
+        half + Duration::from_millis(fastrand::u64(0..=spread))
+    }
+
+    // Send the request built by `build`, retrying transient failures.  The last
+    // response is returned as is, so the caller still checks its status.
+    pub async fn send<F>(&self, build: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut retry = 0;
+        loop {
+            let attempts_left = retry + 1 < self.max_attempts;
+            let (delay, url, reason) = match build().send().await {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    if !attempts_left || !self.is_retryable_status(status) {
+                        return Ok(response);
+                    }
+                    let delay = match retry_after(&response) {
+                        Some(v) => v.min(self.max_retry_after),
+                        None => self.backoff(retry),
+                    };
+                    (delay, response.url().to_string(), status.to_string())
+                }
+                Err(e) => {
+                    if !attempts_left || !self.is_retryable_error(&e) {
+                        return Err(e);
+                    }
+                    let url = e.url().map(|v| v.to_string()).unwrap_or_default();
+                    (self.backoff(retry), url, e.to_string())
+                }
+            };
+            if let Some(hook) = &self.on_retry {
+                hook(&Retry {
+                    url,
+                    retry: retry + 1,
+                    delay,
+                    reason,
+                });
+            }
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value)
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = date.with_timezone(&Utc) - Utc::now();
+    Some(delay.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_retry_after, RetryPolicy};
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(1000))
+            .with_jitter(false);
+        assert!(policy.backoff(0) == Duration::from_millis(100));
+        assert!(policy.backoff(1) == Duration::from_millis(200));
+        assert!(policy.backoff(3) == Duration::from_millis(800));
+        assert!(policy.backoff(4) == Duration::from_millis(1000));
+        assert!(policy.backoff(40) == Duration::from_millis(1000));
+
+        let policy = policy.with_jitter(true);
+        for _ in 0..100 {
+            let v = policy.backoff(2);
+            assert!(v >= Duration::from_millis(200) && v <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert!(parse_retry_after("3") == Some(Duration::from_secs(3)));
+        assert!(parse_retry_after(" 120 ") == Some(Duration::from_secs(120)));
+        assert!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT") == Some(Duration::ZERO));
+        assert!(parse_retry_after("soon").is_none());
+
+        let later = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        match parse_retry_after(&later) {
+            Some(v) => assert!(v > Duration::from_secs(50) && v <= Duration::from_secs(60)),
+            None => panic!("Failed to parse {later}"),
+        }
+    }
+
+    // Serve the canned responses in order, one per connection
+    async fn serve(responses: Vec<&'static str>) -> String {
+        let listener = match TcpListener::bind("127.0.0.1:0").await {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to bind: {e}"),
+        };
+        let addr = match listener.local_addr() {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to get address: {e}"),
+        };
+        tokio::spawn(async move {
+            for response in responses {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    #[tokio::test]
+    async fn test_send_retries() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+        ])
+        .await;
+
+        let client = reqwest::Client::new();
+        let retries = Arc::new(Mutex::new(vec![]));
+        let seen = retries.clone();
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::ZERO, Duration::ZERO)
+            .with_on_retry(move |v| {
+                seen.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(v.clone())
+            });
+        match policy.send(|| client.get(&url)).await {
+            Ok(v) => assert!(v.status().as_u16() == 200),
+            Err(e) => panic!("Failed to send: {e}"),
+        }
+
+        let retries = retries.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(retries.len() == 2);
+        assert!(retries[0].retry == 1 && retries[0].reason == "503");
+        assert!(retries[1].retry == 2 && retries[1].reason == "429");
+        assert!(retries[1].url == url);
+    }
+
+    #[tokio::test]
+    async fn test_send_gives_up() {
+        let url = serve(vec![
+            "HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy::default()
+            .with_max_attempts(2)
+            .with_backoff(Duration::ZERO, Duration::ZERO);
+        match policy.send(|| client.get(&url)).await {
+            Ok(v) => assert!(v.status().as_u16() == 502),
+            Err(e) => panic!("Failed to send: {e}"),
+        }
+
+        // 404 is not retryable, so it comes straight back
+        let url = serve(vec![
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        match policy.send(|| client.get(&url)).await {
+            Ok(v) => assert!(v.status().as_u16() == 404),
+            Err(e) => panic!("Failed to send: {e}"),
+        }
+    }
+}
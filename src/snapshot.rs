@@ -0,0 +1,455 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::auth::HydrolixAuth;
+use crate::client::HydrolixClient;
+use crate::dump;
+use crate::error::{Error, Result};
+use crate::export;
+use crate::hydrolix::cluster;
+use crate::hydrolix::secrets;
+use crate::redact::Redactor;
+
+// Keeps the per-resource dump of every cluster in a local git repository,
+// one directory per cluster, and commits only when something changed.
+#[derive(Clone)]
+pub struct GitSnapshot {
+    repo: PathBuf,
+    branch: String,
+    remote: Option<String>,
+    token: Option<String>,
+    author_name: String,
+    author_email: String,
+    redactor: Option<Redactor>,
+}
+
+// Never print the token, it ends up in logs
+impl fmt::Debug for GitSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GitSnapshot")
+            .field("repo", &self.repo)
+            .field("branch", &self.branch)
+            .field("remote", &self.remote)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("author_name", &self.author_name)
+            .field("author_email", &self.author_email)
+            .field("redactor", &self.redactor)
+            .finish()
+    }
+}
+
+// What a snapshot commit contained
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub commit: String,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+// One changed resource, e.g. the transform `org1/web/logs/json` on `cluster-1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub cluster: String,
+    pub resource: String,
+    pub path: String,
+    pub file: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ChangeKind::Added => "added",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Removed => "removed",
+        };
+        write!(
+            f,
+            "{kind} {} {} on {}",
+            self.resource, self.path, self.cluster
+        )
+    }
+}
+
+impl GitSnapshot {
+    pub fn new(repo: &Path) -> GitSnapshot {
+        GitSnapshot {
+            repo: repo.to_path_buf(),
+            branch: "main".to_string(),
+            remote: None,
+            token: None,
+            author_name: "hydrolix_helpers".to_string(),
+            author_email: "hydrolix_helpers@localhost".to_string(),
+            // The repository is meant to be shared
+            redactor: Some(Redactor::default()),
+        }
+    }
+
+    // Push to the remote with the `git_snapshots.token` from the secrets file
+    pub fn from_config(repo: &Path, config: &secrets::Config, remote: &str) -> GitSnapshot {
+        GitSnapshot::new(repo)
+            .with_remote(remote)
+            .with_token(&config.git_snapshots.token)
+    }
+
+    // Push every snapshot to this URL or path
+    pub fn with_remote(mut self, remote: &str) -> Self {
+        self.remote = Some(remote.to_string());
+        self
+    }
+
+    // Token for pushing over HTTPS.  It is passed to git through the
+    // environment, never on the command line or in the remote URL.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = if token.is_empty() {
+            None
+        } else {
+            Some(token.to_string())
+        };
+        self
+    }
+
+    pub fn with_branch(mut self, branch: &str) -> Self {
+        self.branch = branch.to_string();
+        self
+    }
+
+    pub fn with_author(mut self, name: &str, email: &str) -> Self {
+        self.author_name = name.to_string();
+        self.author_email = email.to_string();
+        self
+    }
+
+    // Redact secrets before they are written.  The built-in rules are used
+    // unless another redactor is set.
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    // Write secrets in clear text, only for a repository nobody else reads
+    pub fn without_redaction(mut self) -> Self {
+        self.redactor = None;
+        self
+    }
+
+    // Directory of a cluster inside the repository
+    pub fn cluster_dir(&self, cluster: &cluster::Cluster) -> PathBuf {
+        self.repo.join(export::sanitize(&cluster.base_url))
+    }
+
+    // Create the repository if needed
+    pub async fn init(&self) -> Result<()> {
+        if self.repo.join(".git").exists() {
+            return Ok(());
+        }
+        if let Err(e) = std::fs::create_dir_all(&self.repo) {
+            return Err(Error::Io {
+                path: self.repo.display().to_string(),
+                source: e,
+            });
+        }
+        self.git(&["init", "-q", "-b", &self.branch]).await?;
+        Ok(())
+    }
+
+    // Write the clusters, commit if anything changed and push if a remote is
+    // set.  Returns `None` when the configuration did not change.
+    pub async fn snapshot(&self, clusters: &[cluster::Cluster]) -> Result<Option<Snapshot>> {
+        self.init().await?;
+
+        for c in clusters {
+            match &self.redactor {
+                Some(r) => export::export_tree(&r.redact(c)?, &self.cluster_dir(c))?,
+                None => export::export_tree(c, &self.cluster_dir(c))?,
+            }
+        }
+
+        self.git(&["add", "-A"]).await?;
+        let status = self
+            .git(&["diff", "--cached", "--name-status", "--no-renames"])
+            .await?;
+        let changes: Vec<Change> = status.lines().filter_map(parse_change).collect();
+        if changes.is_empty() {
+            return Ok(None);
+        }
+
+        let message = commit_message(&changes);
+        let name = format!("user.name={}", self.author_name);
+        let email = format!("user.email={}", self.author_email);
+        self.git(&["-c", &name, "-c", &email, "commit", "-q", "-m", &message])
+            .await?;
+        let commit = self.git(&["rev-parse", "HEAD"]).await?.trim().to_string();
+
+        if self.remote.is_some() {
+            self.push().await?;
+        }
+
+        Ok(Some(Snapshot { commit, changes }))
+    }
+
+    // Log in to every machine in the secrets file, dump it and snapshot the lot
+    pub async fn snapshot_config(
+        &self,
+        client: &HydrolixClient,
+        config: &secrets::Config,
+    ) -> Result<Option<Snapshot>> {
+        let mut clusters = vec![];
+        for m in &config.machines {
+            let auth = HydrolixAuth::new(&m.base_url, &m.username, &m.password)
+                .await
+                .with_client(client.clone());
+            let token = auth.get_token().await?;
+            clusters.push(*dump::dump_with_client(client, &token).await?);
+        }
+        self.snapshot(&clusters).await
+    }
+
+    pub async fn push(&self) -> Result<()> {
+        let Some(remote) = &self.remote else {
+            return Ok(());
+        };
+        let refspec = format!("HEAD:refs/heads/{}", self.branch);
+        self.git(&["push", "-q", remote, &refspec]).await?;
+        Ok(())
+    }
+
+    async fn git(&self, args: &[&str]) -> Result<String> {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(&self.repo).args(args);
+
+        // `http.extraHeader` through the environment keeps the token out of
+        // the process list and out of error messages
+        if let Some(token) = &self.token {
+            let credentials = STANDARD.encode(format!("x-access-token:{token}"));
+            command
+                .env("GIT_CONFIG_COUNT", "1")
+                .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+                .env(
+                    "GIT_CONFIG_VALUE_0",
+                    format!("Authorization: Basic {credentials}"),
+                );
+        }
+
+        let output = match command.output().await {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(Error::Command {
+                    command: format!("git {}", args.join(" ")),
+                    status: None,
+                    stderr: e.to_string(),
+                })
+            }
+        };
+        if !output.status.success() {
+            return Err(Error::Command {
+                command: format!("git {}", args.join(" ")),
+                status: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+// Turn a `git diff --name-status` line into a change, e.g.
+// `M\tcluster-1/org/org1/project/web/table/logs/transform/json.json`
+// `is_multiple_of` would need Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn parse_change(line: &str) -> Option<Change> {
+    let (status, file) = line.split_once('\t')?;
+    let kind = match status {
+        "A" => ChangeKind::Added,
+        "D" => ChangeKind::Removed,
+        _ => ChangeKind::Modified,
+    };
+
+    let parts: Vec<&str> = file
+        .strip_suffix(".json")
+        .unwrap_or(file)
+        .split('/')
+        .collect();
+    let cluster = parts.first()?.to_string();
+    let rest = &parts[1..];
+
+    // `<kind>/<name>` pairs, ending in either `<kind>.json` for the resource
+    // owning the directory or `<kind>/<name>.json` for a leaf resource
+    let (resource, names) = match rest {
+        [] | ["cluster"] => ("cluster".to_string(), vec![]),
+        _ if rest.len() % 2 == 0 => {
+            let names = rest.iter().skip(1).step_by(2).copied().collect();
+            (rest[rest.len() - 2].to_string(), names)
+        }
+        _ => {
+            let names = rest.iter().skip(1).step_by(2).copied().collect();
+            (rest[rest.len() - 1].to_string(), names)
+        }
+    };
+
+    Some(Change {
+        kind,
+        cluster,
+        resource,
+        path: names.join("/"),
+        file: file.to_string(),
+    })
+}
+
+fn commit_message(changes: &[Change]) -> String {
+    let mut clusters: Vec<&str> = changes.iter().map(|v| v.cluster.as_str()).collect();
+    clusters.dedup();
+    let noun = if changes.len() == 1 {
+        "change"
+    } else {
+        "changes"
+    };
+    let mut message = format!(
+        "Snapshot {}: {} {noun}\n\n",
+        clusters.join(", "),
+        changes.len()
+    );
+    for c in changes {
+        message += &format!("- {c}\n");
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_change, ChangeKind, GitSnapshot};
+    use crate::dump::{dump_helper, DumpOptions};
+    use crate::hydrolix::secrets;
+    use crate::mock;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn test_parse_change() {
+        let change = match parse_change("M\th/org/org1/project/p1/table/t2/transform/x.json") {
+            Some(v) => v,
+            None => panic!("Failed to parse"),
+        };
+        assert!(change.kind == ChangeKind::Modified);
+        assert!(change.cluster == "h");
+        assert!(change.resource == "transform");
+        assert!(change.path == "org1/p1/t2/x");
+        assert!(change.to_string() == "modified transform org1/p1/t2/x on h");
+
+        match parse_change("A\th/org/org1/project/p1/table/t2/table.json") {
+            Some(v) => assert!(v.resource == "table" && v.path == "org1/p1/t2"),
+            None => panic!("Failed to parse"),
+        }
+        match parse_change("D\th/org/org1/storage/s1.json") {
+            Some(v) => assert!(v.kind == ChangeKind::Removed && v.resource == "storage"),
+            None => panic!("Failed to parse"),
+        }
+        match parse_change("A\th/cluster.json") {
+            Some(v) => assert!(v.resource == "cluster" && v.path.is_empty()),
+            None => panic!("Failed to parse"),
+        }
+        // Only the file extension is dropped from the name
+        match parse_change("A\th/org/org1/storage/s1.json.json") {
+            Some(v) => assert!(v.path == "org1/s1.json"),
+            None => panic!("Failed to parse"),
+        }
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = match secrets::Config::parse(
+            "machines = []\n[git_snapshots]\ntoken = \"ghp_secret\"\n",
+        ) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to parse config: {e}"),
+        };
+        let snapshot = GitSnapshot::from_config(Path::new("/tmp/x"), &config, "https://g/r.git");
+        assert!(snapshot.token.as_deref() == Some("ghp_secret"));
+        assert!(!format!("{snapshot:?}").contains("ghp_secret"));
+    }
+
+    fn git_log(git_dir: &Path) -> String {
+        match Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir)
+            .args(["log", "--format=%B", "main"])
+            .output()
+        {
+            Ok(v) => String::from_utf8_lossy(&v.stdout).to_string(),
+            Err(e) => panic!("Failed to run git: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_to_bare_repo() {
+        let root = std::env::temp_dir().join(format!("hdx_snapshot_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let bare = root.join("remote.git");
+        let status = Command::new("git")
+            .args(["init", "-q", "--bare", "-b", "main"])
+            .arg(&bare)
+            .status();
+        assert!(status.is_ok_and(|v| v.success()));
+
+        let (cluster, _) =
+            match dump_helper(&mock::cluster(), &mock::token(), &DumpOptions::default()).await {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to dump: {e}"),
+            };
+
+        let remote = bare.display().to_string();
+        // Redacted by default
+        let snapshot = GitSnapshot::new(&root.join("work")).with_remote(&remote);
+        match snapshot.snapshot(&[*cluster.clone()]).await {
+            Ok(Some(v)) => {
+                assert!(!v.commit.is_empty());
+                assert!(v.changes.iter().all(|c| c.kind == ChangeKind::Added));
+            }
+            Ok(None) => panic!("The first snapshot should commit"),
+            Err(e) => panic!("Failed to snapshot: {e}"),
+        }
+        let table = root.join("work/h/org/org1/project/p1/table/t1/table.json");
+        assert!(fs::read_to_string(table).is_ok_and(|v| !v.contains("stream-secret")));
+
+        // Nothing changed, nothing committed
+        match snapshot.snapshot(&[*cluster.clone()]).await {
+            Ok(v) => assert!(v.is_none()),
+            Err(e) => panic!("Failed to snapshot: {e}"),
+        }
+
+        let mut changed = cluster.clone();
+        if let Some(orgs) = &mut changed.orgs {
+            if let Some(projects) = &mut orgs[0].projects {
+                projects[0].description = Some("updated".to_string());
+                projects.truncate(1);
+            }
+        }
+        match snapshot.snapshot(&[*changed]).await {
+            Ok(Some(v)) => {
+                let text: Vec<String> = v.changes.iter().map(|c| c.to_string()).collect();
+                assert!(text.contains(&"modified project org1/p1 on h".to_string()));
+                assert!(text.contains(&"removed project org1/p2 on h".to_string()));
+                assert!(
+                    text.contains(&"removed transform org1/p2/t3/t3_transform on h".to_string())
+                );
+            }
+            Ok(None) => panic!("The change should commit"),
+            Err(e) => panic!("Failed to snapshot: {e}"),
+        }
+
+        let log = git_log(&bare);
+        assert!(log.contains("modified project org1/p1 on h"));
+        assert!(log.matches("Snapshot h:").count() == 2);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}